@@ -34,6 +34,10 @@ const HEAP_SIZE: usize = 1024; // in bytes
 fn main() -> ! {
     // Initialize the allocator BEFORE you use it
     unsafe { ALLOCATOR.init(cortex_m_rt::heap_start() as usize, HEAP_SIZE) }
+    // With checkpoints (src/checkpoint), register the arena and the allocator
+    // here so frames carry them:
+    //   register_heap(heap_start, HEAP_SIZE as u32, &ALLOCATOR as *const _ as u32,
+    //                 core::mem::size_of::<CortexMHeap>() as u32, None)
 
     // Growable array allocated on the heap
     let xs = vec![0, 1, 2];
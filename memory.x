@@ -4,11 +4,27 @@ MEMORY
   /* NOTE 1 K = 1 KiBi = 1024 bytes */
   /* TODO Adjust these memory regions to match your device memory layout */
   /* These values correspond to the LM3S6965, one of the few devices QEMU can emulate */
-  FLASH : ORIGIN = 0x08000000, LENGTH = 512K
+  /* program, the rest of the 512K flash is kept for checkpoints */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 192K
+  CHECKPOINT : ORIGIN = 0x08030000, LENGTH = 320K
   RAM : ORIGIN = 0x20000000, LENGTH = 64K
-  FRAM : ORIGIN = 0x60000000, LENGTH = 32K
+  /* F-RAM: persistent variables (.fram_section), the undo log and the copy of
+     the last checkpointed stack used for delta checkpoints */
+  FRAM : ORIGIN = 0x60000000, LENGTH = 16K
+  UNDO_LOG : ORIGIN = 0x60004000, LENGTH = 4K
+  STACK_SHADOW : ORIGIN = 0x60005000, LENGTH = 12K
 }
 
+/* Regions used by src/checkpoint */
+_checkpoint_start = ORIGIN(CHECKPOINT);
+_checkpoint_end = ORIGIN(CHECKPOINT) + LENGTH(CHECKPOINT);
+_undo_log_start = ORIGIN(UNDO_LOG);
+_undo_log_end = ORIGIN(UNDO_LOG) + LENGTH(UNDO_LOG);
+_persistent_start = ORIGIN(FRAM);
+_persistent_end = ORIGIN(FRAM) + LENGTH(FRAM);
+_stack_shadow_start = ORIGIN(STACK_SHADOW);
+_stack_shadow_end = ORIGIN(STACK_SHADOW) + LENGTH(STACK_SHADOW);
+
 /* This is where the call stack will be allocated. */
 /* The stack is of the full descending type. */
 /* You may want to use this variable to locate the call stack and static
@@ -48,4 +64,21 @@ _stack_start = _estack;
 
 /* Specify the stack section location and size */
 PROVIDE(_stack_start = _stack_start);
-PROVIDE(_stack_end = _estack - STACK_SIZE);
\ No newline at end of file
+PROVIDE(_stack_end = _estack - STACK_SIZE);
+
+/* Power-fail checkpoint: bind the interrupt straight to the checkpoint entry,
+   it needs EXC_RETURN in lr (see context.rs), e.g.
+EXTI0 = checkpoint_from_exception;
+*/
+
+/* Checkpoint layout checks */
+/* bank.rs splits the area into 8 banks of whole 2K pages */
+ASSERT(_checkpoint_start % 2048 == 0, "checkpoint region must start on a flash page");
+ASSERT((_checkpoint_end - _checkpoint_start) % (8 * 2048) == 0, "checkpoint region must hold 8 banks of whole pages");
+/* wear.rs keeps erase counters for at most 256 pages */
+ASSERT((_checkpoint_end - _checkpoint_start) <= 256 * 2048, "checkpoint region is larger than the wear table");
+ASSERT(LOADADDR(.data) + SIZEOF(.data) <= _checkpoint_start, "program runs into the checkpoint region");
+ASSERT(_persistent_end <= _undo_log_start, "persistent variables overlap the undo log");
+ASSERT(ADDR(.fram_section) + SIZEOF(.fram_section) <= _persistent_end, ".fram_section runs into the undo log");
+ASSERT(_undo_log_end <= _stack_shadow_start, "undo log overlaps the stack shadow");
+ASSERT(_stack_shadow_end - _stack_shadow_start >= STACK_SIZE, "stack shadow is smaller than the stack");
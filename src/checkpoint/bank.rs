@@ -0,0 +1,235 @@
+#![allow(unsafe_code)]
+use core::ptr;
+use stm32f3xx_hal_v2::pac::FLASH;
+
+use super::frame::{FrameHeader, HEADER_SIZE};
+use super::layout::{checkpoint_start, checkpoint_end};
+use super::history::Needed;
+use super::wear::{erase_counted, range_erase_count};
+
+// The checkpoint area is split into banks. Frames are appended to the active
+// bank; when it is full the next frame goes to the start of a freshly erased
+// bank and the old bank is only erased after that frame is committed, so
+// there is always a committed checkpoint somewhere in flash.
+//
+// The next bank is the least worn one (see wear.rs), so the log rotates over
+// the whole area instead of always restarting at CHECKPOINT_START.
+
+pub const PAGE_SIZE: u32 = 2 * 1024;
+// memory.x checks that the checkpoint region splits into BANK_COUNT banks of whole pages
+pub const BANK_COUNT: u32 = 8;
+
+pub fn bank_size() -> u32 {
+    (checkpoint_end() - checkpoint_start()) / BANK_COUNT
+}
+
+pub fn bank_start(bank: u32) -> u32 {
+    checkpoint_start() + bank * bank_size()
+}
+
+pub fn erase_bank(flash: &mut FLASH, bank: u32) {
+    let mut page = bank_start(bank);
+    let end = page + bank_size();
+    while page < end {
+        if !page_is_erased(page) {
+            erase_counted(flash, page);
+        }
+        page += PAGE_SIZE;
+    }
+}
+
+// first page of `bank` that is not blank
+pub fn dirty_page(bank: u32) -> Option<u32> {
+    (0..bank_size() / PAGE_SIZE)
+        .map(|i| bank_start(bank) + i * PAGE_SIZE)
+        .find(|page| !page_is_erased(*page))
+}
+
+fn page_is_erased(page: u32) -> bool {
+    (0..PAGE_SIZE / 4).all(|i| unsafe { ptr::read_volatile((page + i * 4) as *const u32) } == 0xffff_ffff)
+}
+
+pub fn bank_erase_count(bank: u32) -> u32 {
+    range_erase_count(bank_start(bank), bank_size() / PAGE_SIZE)
+}
+
+// least worn bank other than `active`, ties go to the bank following `active`;
+// banks holding retained frames are only used when there is nothing else
+fn next_bank(active: u32, needed: &Needed) -> u32 {
+    let mut best: Option<(bool, u32, u32)> = None;
+    for i in 1..BANK_COUNT {
+        let bank = (active + i) % BANK_COUNT;
+        let candidate = (needed.bank_is_live(bank), bank_erase_count(bank), bank);
+        if best.is_none_or(|(retained, count, _)| (candidate.0, candidate.1) < (retained, count)) {
+            best = Some(candidate);
+        }
+    }
+    best.map_or((active + 1) % BANK_COUNT, |(_, _, bank)| bank)
+}
+
+// Erase a bank the log has moved away from, unless it still holds retained
+// frames. `needed` is the scan next_slot() made before the bank switch: the
+// frame written since starts the new bank and needs nothing from the old one.
+pub fn retire_bank(flash: &mut FLASH, bank: u32, needed: &Needed) {
+    if !needed.bank_is_live(bank) {
+        erase_bank(flash, bank);
+    }
+}
+
+pub fn bank_of(address: u32) -> u32 {
+    (address - checkpoint_start()) / bank_size()
+}
+
+// Walks the frames of one bank in the order they were written. Stops at the
+// first erased word; a corrupt size word ends the walk with `address` at the
+// end of the bank, nothing can be appended after it.
+pub struct FrameIter {
+    pub address: u32,
+    end: u32,
+}
+
+impl FrameIter {
+    pub fn new(bank: u32) -> FrameIter {
+        FrameIter { address: bank_start(bank), end: bank_start(bank) + bank_size() }
+    }
+}
+
+impl Iterator for FrameIter {
+    type Item = (u32, FrameHeader);
+
+    fn next(&mut self) -> Option<(u32, FrameHeader)> {
+        if self.address >= self.end {
+            return None;
+        }
+        let offset = unsafe { ptr::read_volatile(self.address as *const u32) };
+        if offset == 0xffff_ffff {
+            return None;
+        }
+        // a torn size word can be anything, compare against the room left
+        // so it cannot wrap around
+        if offset < HEADER_SIZE || !offset.is_multiple_of(4) || offset > self.end - self.address {
+            self.address = self.end;
+            return None;
+        }
+        let frame = (self.address, FrameHeader::read(self.address));
+        self.address += offset;
+        Some(frame)
+    }
+}
+
+pub struct BankScan {
+    // first erased word after the last frame, end of the bank if the bank is
+    // full or its last size word is corrupt (nothing can be appended then)
+    pub tail: u32,
+    // newest committed frame by its header, the crc is not checked here
+    pub newest: Option<(u32, FrameHeader)>,
+    // highest sequence number of any frame header, committed or not
+    pub last_seq: Option<u32>,
+}
+
+pub fn scan_bank(bank: u32) -> BankScan {
+    let mut frames = FrameIter::new(bank);
+    let mut newest: Option<(u32, FrameHeader)> = None;
+    let mut last_seq: Option<u32> = None;
+
+    for (address, header) in frames.by_ref() {
+        if header.is_valid() && Some(header.seq) > last_seq {
+            last_seq = Some(header.seq);
+        }
+        if header.is_valid() && header.is_committed() && newest.is_none_or(|(_, n)| header.seq > n.seq) {
+            newest = Some((address, header));
+        }
+    }
+
+    BankScan { tail: frames.address, newest, last_seq }
+}
+
+// Where the log ends, from the frame headers alone; checking crcs on every
+// checkpoint would cost more than writing the frame. restore() picks its
+// frame with newest_intact().
+pub struct LogScan {
+    pub active_bank: u32,
+    pub tail: u32,
+    // newest committed frame over all banks, by its header
+    pub newest: Option<(u32, FrameHeader)>,
+    pub next_seq: u32,
+}
+
+pub fn scan_log() -> LogScan {
+    let mut log = LogScan { active_bank: 0, tail: bank_start(0), newest: None, next_seq: 0 };
+    let mut active_seq: Option<u32> = None;
+
+    for bank in 0..BANK_COUNT {
+        let scan = scan_bank(bank);
+        if let Some(seq) = scan.last_seq {
+            if seq >= log.next_seq {
+                log.next_seq = seq + 1;
+            }
+        }
+        // the bank holding the newest committed frame is the active one, a
+        // bank that only has uncommitted frames counts if nothing is committed
+        let seq = scan.newest.map(|(_, header)| header.seq);
+        let candidate = match (seq, log.newest) {
+            (Some(seq), Some((_, newest))) => seq > newest.seq,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => scan.last_seq > active_seq,
+        };
+        if candidate {
+            log.active_bank = bank;
+            log.tail = scan.tail;
+            active_seq = scan.last_seq;
+            if scan.newest.is_some() {
+                log.newest = scan.newest;
+            }
+        }
+    }
+    log
+}
+
+// Newest committed frame whose crc matches. Candidates are checked newest
+// first (copies of the same frame by address) and the walk stops at the
+// first intact one, a torn or rotten frame falls back to the one before.
+pub fn newest_intact() -> Option<(u32, FrameHeader)> {
+    let mut below: Option<(u32, u32)> = None;
+    loop {
+        let candidate = (0..BANK_COUNT)
+            .flat_map(FrameIter::new)
+            .filter(|(_, header)| header.is_valid() && header.is_committed())
+            .filter(|(address, header)| below.is_none_or(|b| (header.seq, *address) < b))
+            .max_by_key(|(address, header)| (header.seq, *address))?;
+        if candidate.1.is_intact(candidate.0) {
+            return Some(candidate);
+        }
+        below = Some((candidate.1.seq, candidate.0));
+    }
+}
+
+pub struct Slot {
+    pub address: u32,
+    pub seq: u32,
+    // bank to erase once the frame written to `address` is committed, and
+    // what it may still hold for retained frames
+    pub retire: Option<(u32, Needed)>,
+    // newest committed frame, None if there is none or the slot starts a new bank
+    pub last: Option<(u32, FrameHeader)>,
+}
+
+// Find room for a frame of `size` bytes. Switching banks erases the new bank
+// here (pages that are still blank are skipped), the old one is left for the
+// caller to retire after the commit.
+pub fn next_slot(flash: &mut FLASH, size: u32) -> Option<Slot> {
+    if size > bank_size() {
+        return None;
+    }
+    let log = scan_log();
+    let end = bank_start(log.active_bank) + bank_size();
+    if log.tail + size <= end {
+        return Some(Slot { address: log.tail, seq: log.next_seq, retire: None, last: log.newest });
+    }
+
+    let needed = Needed::scan();
+    let bank = next_bank(log.active_bank, &needed);
+    erase_bank(flash, bank);
+    Some(Slot { address: bank_start(bank), seq: log.next_seq, retire: Some((log.active_bank, needed)), last: None })
+}
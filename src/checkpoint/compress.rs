@@ -0,0 +1,124 @@
+#![allow(unsafe_code)]
+use core::ptr;
+use stm32f3xx_hal_v2::pac::FLASH;
+
+use super::crc::crc32_update;
+use super::my_flash::write_to_flash;
+
+// Word run-length encoding for stack images, no allocation and no buffer: the
+// encoder reads the live stack and emits words straight to flash, the decoder
+// writes straight into the destination image.
+//
+// Every token is one word, kind in the top two bits and a word count below:
+//
+//   ZERO    count zero words, nothing follows
+//   REPEAT  count copies of the word that follows
+//   LITERAL count words that follow as they are
+//
+// Only the encoder is behind the `compress` feature (see checkpoint()), restore
+// can always decode.
+
+const KIND_SHIFT: u32 = 30;
+const KIND_ZERO: u32 = 0;
+const KIND_REPEAT: u32 = 1;
+const KIND_LITERAL: u32 = 2;
+const COUNT_MASK: u32 = (1 << KIND_SHIFT) - 1;
+
+fn stack_word(top: u32, index: u32) -> u32 {
+    unsafe { ptr::read_volatile((top - index * 4) as *const u32) }
+}
+
+// words equal to word `i` starting at `i`
+fn run_len(top: u32, words: u32, i: u32) -> u32 {
+    let word = stack_word(top, i);
+    let mut j = i + 1;
+    while j < words && stack_word(top, j) == word {
+        j += 1;
+    }
+    j - i
+}
+
+// Encodes the `words` stack words below `top`, calling emit for each output
+// word.
+fn encode<F: FnMut(u32)>(top: u32, words: u32, mut emit: F) {
+    let mut i = 0;
+    while i < words {
+        let word = stack_word(top, i);
+        let run = run_len(top, words, i);
+        if word == 0 {
+            emit(KIND_ZERO << KIND_SHIFT | run);
+            i += run;
+        } else if run >= 2 {
+            emit(KIND_REPEAT << KIND_SHIFT | run);
+            emit(word);
+            i += run;
+        } else {
+            // literals until the next zero or repeated word
+            let start = i;
+            i += 1;
+            while i < words && stack_word(top, i) != 0 && run_len(top, words, i) < 2 {
+                i += 1;
+            }
+            emit(KIND_LITERAL << KIND_SHIFT | (i - start));
+            for j in start..i {
+                emit(stack_word(top, j));
+            }
+        }
+    }
+}
+
+pub fn compressed_len(top: u32, words: u32) -> u32 {
+    let mut len = 0;
+    encode(top, words, |_| len += 4);
+    len
+}
+
+// Writes the encoded stack at `address`, returns the address after it and the
+// updated crc.
+pub fn write_compressed(flash: &mut FLASH, mut address: u32, top: u32, words: u32, mut crc: u32) -> (u32, u32) {
+    encode(top, words, |word| {
+        write_to_flash(flash, address, word);
+        crc = crc32_update(crc, word);
+        address += 4;
+    });
+    (address, crc)
+}
+
+// Decodes `len` bytes at `src` into `words` words at `dst` (dst + 4 * i is
+// word i). Returns false if the data does not decode to exactly `words` words.
+pub fn decompress(src: u32, len: u32, dst: u32, words: u32) -> bool {
+    let read = |offset: u32| unsafe { ptr::read_volatile((src + offset) as *const u32) };
+    let mut offset = 0;
+    let mut i = 0;
+    while offset < len {
+        let token = read(offset);
+        let count = token & COUNT_MASK;
+        offset += 4;
+        if i + count > words {
+            return false;
+        }
+        match token >> KIND_SHIFT {
+            KIND_ZERO => {
+                for j in i..i + count {
+                    unsafe { ptr::write_volatile((dst + j * 4) as *mut u32, 0) };
+                }
+            }
+            KIND_REPEAT if offset < len => {
+                let word = read(offset);
+                offset += 4;
+                for j in i..i + count {
+                    unsafe { ptr::write_volatile((dst + j * 4) as *mut u32, word) };
+                }
+            }
+            KIND_LITERAL if offset + count * 4 <= len => {
+                for j in 0..count {
+                    unsafe { ptr::write_volatile((dst + (i + j) * 4) as *mut u32, read(offset + j * 4)) };
+                }
+                offset += count * 4;
+            }
+            _ => return false,
+        }
+        i += count;
+    }
+    i == words
+}
@@ -0,0 +1,329 @@
+#![allow(unsafe_code)]
+use core::arch::global_asm;
+use core::ptr;
+
+// Register capture and resume, in assembly so nothing depends on the stack
+// frame rustc gives checkpoint() at a given opt-level.
+//
+// Register block of a frame (REGISTER_BLOCK_SIZE bytes):
+//
+//   r0 - r12, sp, lr, pc, xPSR, PRIMASK, BASEPRI, FAULTMASK, CONTROL, MSP, PSP
+//
+// sp is the caller's stack pointer when checkpoint() was entered and pc the
+// address it returns to; resuming a frame looks to the caller like checkpoint()
+// returning a second time. Whichever of MSP and PSP is the active stack
+// pointer holds the same value as sp.
+//
+// Restore order matters: the stack image is pushed first, then the inactive
+// stack pointer and the interrupt masks are written, CONTROL goes after the
+// masks (it can drop privilege, after which they can no longer be written) and
+// the APSR flags go last, once nothing else sets flags.
+//
+// With the FPU enabled in CPACR the block is followed by S0 - S31 and FPSCR
+// (FPU_BLOCK_SIZE bytes, FLAG_FPU). With it disabled any VFP instruction
+// faults, so they are neither saved nor touched.
+
+pub const REG_SP: usize = 13;
+pub const REG_LR: usize = 14;
+pub const REG_PC: usize = 15;
+pub const REG_XPSR: usize = 16;
+pub const REG_PRIMASK: usize = 17;
+pub const REG_BASEPRI: usize = 18;
+pub const REG_FAULTMASK: usize = 19;
+pub const REG_CONTROL: usize = 20;
+pub const REG_MSP: usize = 21;
+pub const REG_PSP: usize = 22;
+pub const REGISTER_COUNT: usize = 23;
+// S0 - S31, FPSCR
+pub const FPU_REGISTER_COUNT: usize = 33;
+// IPSR, the exception number, 0 in Thread mode
+pub const XPSR_IPSR: u32 = 0x1ff;
+// ICI/IT bits of xPSR, xPSR[26:25] and xPSR[15:10]
+pub const XPSR_ICI_IT: u32 = 0x0600_fc00;
+
+const CPACR: u32 = 0xE000_ED88;
+// CP10 and CP11 full access
+const CPACR_FPU: u32 = 0xf << 20;
+
+// what __checkpoint_capture leaves below the caller's sp for save_frame()
+#[repr(C)]
+pub struct Context {
+    pub registers: [u32; REGISTER_COUNT],
+    // non zero if fpu holds S0 - S31 and FPSCR
+    pub fpu_saved: u32,
+    pub fpu: [u32; FPU_REGISTER_COUNT],
+    // scratch word, the exception entry keeps its callback here
+    _pad: u32,
+}
+
+extern "C" {
+    // builds a Context below the caller's sp and returns what
+    // save_frame(context, c_type) returns
+    pub fn __checkpoint_capture(c_type: bool) -> u32;
+    // exception handler version of __checkpoint_capture, see below
+    pub fn checkpoint_from_exception();
+    // pushes `words` stack words back below `top` (word 0 ends up right below
+    // `top`, word i is at image + 4 * i), reloads the FPU registers from `fpu`
+    // unless it is 0 and jumps into the register block with r0 = `r0`
+    pub fn __checkpoint_resume(image: u32, words: u32, registers: u32, top: u32, fpu: u32, r0: u32) -> !;
+}
+
+// the FPU registers of a frame can only be reloaded with CP10/CP11 enabled
+pub fn enable_fpu() {
+    unsafe {
+        let cpacr = ptr::read_volatile(CPACR as *const u32);
+        ptr::write_volatile(CPACR as *mut u32, cpacr | CPACR_FPU);
+    }
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+}
+
+// Context is 58 words, 232 bytes keeps sp 8 byte aligned for the call. The
+// flags are read before anything here can change them.
+global_asm!(
+    ".section .text.__checkpoint_capture,\"ax\",%progbits",
+    ".global __checkpoint_capture",
+    ".type __checkpoint_capture,%function",
+    ".thumb_func",
+    "__checkpoint_capture:",
+    "sub sp, sp, #232",
+    "stm sp, {{r0-r12}}",
+    "mrs r1, xpsr",
+    "str r1, [sp, #64]",
+    "add r1, sp, #232",
+    "str r1, [sp, #52]",
+    "str lr, [sp, #56]",
+    "str lr, [sp, #60]",
+    "mrs r1, primask",
+    "str r1, [sp, #68]",
+    "mrs r1, basepri",
+    "str r1, [sp, #72]",
+    "mrs r1, faultmask",
+    "str r1, [sp, #76]",
+    "mrs r1, msp",
+    "str r1, [sp, #84]",
+    "mrs r1, psp",
+    "str r1, [sp, #88]",
+    "mrs r1, control",
+    "str r1, [sp, #80]",
+    // the active stack pointer is the caller's sp, not this frame's
+    "add r2, sp, #232",
+    "tst r1, #2",
+    "ite eq",
+    "streq r2, [sp, #84]",
+    "strne r2, [sp, #88]",
+    "movw r1, #0xed88",
+    "movt r1, #0xe000",
+    "ldr r1, [r1]",
+    "and r1, r1, #0xf00000",
+    "cmp r1, #0xf00000",
+    "mov r1, #0",
+    "bne 1f",
+    "add r2, sp, #96",
+    "vstmia r2!, {{s0-s31}}",
+    "vmrs r3, fpscr",
+    "str r3, [r2]",
+    "mov r1, #1",
+    "1:",
+    "str r1, [sp, #92]",
+    "mov r1, r0",
+    "mov r0, sp",
+    "bl save_frame",
+    "ldr lr, [sp, #56]",
+    "add sp, sp, #232",
+    "bx lr",
+    ".size __checkpoint_capture, . - __checkpoint_capture",
+);
+
+// The stack image overwrites whatever the caller had on the stack, so this
+// never returns. pc is pushed just below the restored sp and popped last, the
+// register block stays in flash (or F-RAM) until ldm is done with it, r0 is
+// pushed below pc and replaces the block's r0. `fpu` and `r0` come in on the
+// stack and are loaded before sp moves.
+//
+// The image is always the main stack and pushed on MSP, which is left at the
+// saved MSP. If the frame was taken on PSP, pc and r0 go on the task stack
+// (put back by restore_tasks() before) instead and CONTROL switches to it.
+global_asm!(
+    ".section .text.__checkpoint_resume,\"ax\",%progbits",
+    ".global __checkpoint_resume",
+    ".type __checkpoint_resume,%function",
+    ".thumb_func",
+    "__checkpoint_resume:",
+    "ldr lr, [sp, #4]",
+    "ldr r12, [sp]",
+    "cmp r12, #0",
+    "beq 1f",
+    "vldmia r12!, {{s0-s31}}",
+    "ldr r12, [r12]",
+    "vmsr fpscr, r12",
+    "1:",
+    "mov sp, r3",
+    "sub r0, r0, #4",
+    "2:",
+    "ldr r3, [r0, #4]!",
+    "push {{r3}}",
+    "subs r1, r1, #1",
+    "bne 2b",
+    "ldr r3, [r2, #60]",
+    "orr r3, r3, #1",
+    "ldr r0, [r2, #80]",
+    "tst r0, #2",
+    "bne 4f",
+    "push {{r3}}",
+    "push {{lr}}",
+    "ldr r1, [r2, #88]",
+    "msr psp, r1",
+    "b 5f",
+    "4:",
+    "ldr r1, [r2, #88]",
+    "str r3, [r1, #-4]",
+    "str lr, [r1, #-8]",
+    "sub r1, r1, #8",
+    "msr psp, r1",
+    "5:",
+    "ldr r1, [r2, #72]",
+    "msr basepri, r1",
+    "ldr r1, [r2, #76]",
+    "msr faultmask, r1",
+    "ldr r1, [r2, #68]",
+    "msr primask, r1",
+    "msr control, r0",
+    "isb",
+    "ldr r1, [r2, #64]",
+    "msr apsr_nzcvqg, r1",
+    "ldr lr, [r2, #56]",
+    "ldm r2, {{r0-r12}}",
+    "pop {{r0}}",
+    "pop {{pc}}",
+    ".size __checkpoint_resume, . - __checkpoint_resume",
+);
+
+// Checkpoint of the thread an exception interrupted, meant to be the handler
+// itself (e.g. `EXTI0 = checkpoint_from_exception;` in memory.x) so lr still
+// holds EXC_RETURN on entry. r0 - r3, r12, lr, pc and xPSR come from the
+// stacked exception frame, r4 - r11, the masks and the FPU registers are still
+// the thread's. The thread's sp is the address above the stacked frame
+// (EXC_RETURN bit 4 clear means an extended frame with S0 - S15, FPSCR, xPSR
+// bit 9 means it was aligned with an extra word), CONTROL gets back the
+// SPSEL and FPCA bits exception entry cleared. Restoring the frame resumes at
+// the interrupted instruction.
+//
+// PendSV (see request_checkpoint()) goes through the same entry, r2 selects
+// the function that gets the context, r3 is set for PendSV.
+//
+// Only a Thread mode context is saved (EXC_RETURN bit 3 set). An exception
+// that preempted another handler would save that handler's context, which
+// cannot be resumed in Thread mode, so checkpoint_deferred() is called
+// instead. The same goes for a thread stopped inside an IT block or an
+// interrupted LDM/STM (ICI/IT bits of the stacked xPSR, XPSR_ICI_IT): resuming
+// goes through msr apsr, not an exception return, and cannot put them back.
+global_asm!(
+    ".section .text.checkpoint_from_exception,\"ax\",%progbits",
+    ".global checkpoint_from_exception",
+    ".type checkpoint_from_exception,%function",
+    ".thumb_func",
+    "checkpoint_from_exception:",
+    "movw r2, #:lower16:save_exception_frame",
+    "movt r2, #:upper16:save_exception_frame",
+    "mov r3, #0",
+    "b __checkpoint_exception",
+    ".size checkpoint_from_exception, . - checkpoint_from_exception",
+    "",
+    ".global PendSV",
+    ".type PendSV,%function",
+    ".thumb_func",
+    "PendSV:",
+    "movw r2, #:lower16:save_pendsv_frame",
+    "movt r2, #:upper16:save_pendsv_frame",
+    "mov r3, #1",
+    "b __checkpoint_exception",
+    ".size PendSV, . - PendSV",
+    "",
+    ".type __checkpoint_exception,%function",
+    ".thumb_func",
+    "__checkpoint_exception:",
+    "tst lr, #8",
+    "beq 3f",
+    "tst lr, #4",
+    "ite eq",
+    "mrseq r12, msp",
+    "mrsne r12, psp",
+    "ldr r0, [r12, #28]",
+    "movw r1, #0xfc00",
+    "movt r1, #0x0600",
+    "tst r0, r1",
+    "beq 2f",
+    "3:",
+    "mov r0, r3",
+    "push {{r0, lr}}",
+    "bl checkpoint_deferred",
+    "pop {{r0, lr}}",
+    "bx lr",
+    "2:",
+    "sub sp, sp, #232",
+    "str r2, [sp, #228]",
+    "ldm r12, {{r0-r3}}",
+    "stm sp, {{r0-r11}}",
+    "ldr r0, [r12, #16]",
+    "str r0, [sp, #48]",
+    "ldr r0, [r12, #20]",
+    "str r0, [sp, #56]",
+    "ldr r0, [r12, #24]",
+    "str r0, [sp, #60]",
+    "ldr r0, [r12, #28]",
+    "str r0, [sp, #64]",
+    "tst lr, #16",
+    "ite eq",
+    "addeq r1, r12, #104",
+    "addne r1, r12, #32",
+    "tst r0, #0x200",
+    "it ne",
+    "addne r1, r1, #4",
+    "str r1, [sp, #52]",
+    "mrs r0, primask",
+    "str r0, [sp, #68]",
+    "mrs r0, basepri",
+    "str r0, [sp, #72]",
+    "mrs r0, faultmask",
+    "str r0, [sp, #76]",
+    "add r0, sp, #232",
+    "str r0, [sp, #84]",
+    "mrs r0, psp",
+    "str r0, [sp, #88]",
+    "tst lr, #4",
+    "ite eq",
+    "streq r1, [sp, #84]",
+    "strne r1, [sp, #88]",
+    "mrs r0, control",
+    "bic r0, r0, #6",
+    "tst lr, #4",
+    "it ne",
+    "orrne r0, r0, #2",
+    "tst lr, #16",
+    "it eq",
+    "orreq r0, r0, #4",
+    "str r0, [sp, #80]",
+    "movw r1, #0xed88",
+    "movt r1, #0xe000",
+    "ldr r1, [r1]",
+    "and r1, r1, #0xf00000",
+    "cmp r1, #0xf00000",
+    "mov r1, #0",
+    "bne 1f",
+    "add r2, sp, #96",
+    "vstmia r2!, {{s0-s31}}",
+    "vmrs r3, fpscr",
+    "str r3, [r2]",
+    "mov r1, #1",
+    "1:",
+    "str r1, [sp, #92]",
+    "ldr r2, [sp, #228]",
+    "mov r0, sp",
+    "push {{r0, lr}}",
+    "blx r2",
+    "pop {{r0, lr}}",
+    "add sp, sp, #232",
+    "bx lr",
+    ".size __checkpoint_exception, . - __checkpoint_exception",
+);
@@ -0,0 +1,40 @@
+#![allow(unsafe_code)]
+use core::ptr;
+
+// CRC-32 (IEEE 802.3, reflected polynomial 0xEDB8_8320).
+// Uses a 16 entry nibble table, a full 256 entry table costs 1K of flash
+// and is not needed at checkpoint sizes.
+
+pub const CRC_INIT: u32 = 0xffff_ffff;
+
+const NIBBLE_TABLE: [u32; 16] = [
+    0x0000_0000, 0x1DB7_1064, 0x3B6E_20C8, 0x26D9_30AC,
+    0x76DC_4190, 0x6B6B_51F4, 0x4DB2_6158, 0x5005_713C,
+    0xEDB8_8320, 0xF00F_9344, 0xD6D6_A3E8, 0xCB61_B38C,
+    0x9B64_C2B0, 0x86D3_D2D4, 0xA00A_E278, 0xBDBD_F21C,
+];
+
+pub fn crc32_update(mut crc: u32, word: u32) -> u32 {
+    for byte in word.to_le_bytes() {
+        crc ^= byte as u32;
+        crc = (crc >> 4) ^ NIBBLE_TABLE[(crc & 0xf) as usize];
+        crc = (crc >> 4) ^ NIBBLE_TABLE[(crc & 0xf) as usize];
+    }
+    crc
+}
+
+pub fn crc32_finish(crc: u32) -> u32 {
+    !crc
+}
+
+// crc of `len` bytes (multiple of 4) of memory mapped flash starting at `addr`
+pub fn crc32_flash(addr: u32, len: u32) -> u32 {
+    let mut crc = CRC_INIT;
+    let mut offset = 0;
+    while offset < len {
+        let word = unsafe { ptr::read_volatile((addr + offset) as *const u32) };
+        crc = crc32_update(crc, word);
+        offset += 4;
+    }
+    crc32_finish(crc)
+}
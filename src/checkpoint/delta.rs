@@ -0,0 +1,215 @@
+#![allow(unsafe_code, non_upper_case_globals)]
+use core::ptr::{self, addr_of, addr_of_mut};
+use stm32f3xx_hal_v2::pac::FLASH;
+
+use super::bank::{bank_of, FrameIter};
+use super::crc::crc32_update;
+use super::compress::decompress;
+use super::frame::{FrameHeader, HEADER_SIZE, ENCODING_RAW, ENCODING_WORD_RLE};
+use super::layout::{stack_shadow_start, stack_shadow_end};
+use super::my_flash::write_to_flash;
+
+// Incremental stack checkpoints.
+//
+// The STACK_SHADOW region in F-RAM holds the stack image of the last committed
+// frame. A delta frame only stores the runs of stack words that differ from it:
+//
+//   run header: start word << 16 | word count, followed by `count` words
+//
+// Word i of a stack image is the word at `top - 4 * i`. Every delta frame
+// records the full frame its chain started from (base_seq); restore() rebuilds
+// the image in the shadow from that base and each committed delta of the same
+// base. A chain never spans banks (the first frame of a bank is always full)
+// and is cut after DELTA_CHAIN_MAX frames so restore stays bounded.
+
+pub const DELTA_CHAIN_MAX: u32 = 8;
+const SHADOW_VALID: u32 = 0x5AD0_0001;
+const MAX_RUN: u32 = 0xffff;
+
+// which frame the shadow currently matches, cleared while the shadow is updated
+#[repr(C)]
+struct ShadowTag {
+    valid: u32,
+    seq: u32,
+    base_seq: u32,
+    words: u32,
+}
+
+#[link_section = ".fram_section"]
+static mut shadow_tag: ShadowTag = ShadowTag { valid: 0, seq: 0, base_seq: 0, words: 0 };
+
+pub fn shadow_capacity() -> u32 {
+    (stack_shadow_end() - stack_shadow_start()) / 4
+}
+
+fn shadow(index: u32) -> *mut u32 {
+    (stack_shadow_start() + index * 4) as *mut u32
+}
+
+fn stack_word(top: u32, index: u32) -> u32 {
+    unsafe { ptr::read_volatile((top - index * 4) as *const u32) }
+}
+
+// base_seq for the frame about to be written as a delta of `last`, None if it
+// has to be a full frame
+pub fn delta_base(last: Option<(u32, FrameHeader)>, address: u32, seq: u32, words: u32) -> Option<u32> {
+    let (last_address, last) = last?;
+    let tag = unsafe { &*addr_of!(shadow_tag) };
+    if tag.valid != SHADOW_VALID || tag.seq != last.seq || words > shadow_capacity() {
+        return None;
+    }
+    if bank_of(last_address) != bank_of(address) || seq - tag.base_seq > DELTA_CHAIN_MAX {
+        return None;
+    }
+    Some(tag.base_seq)
+}
+
+// calls f(start, count) for every run of words that differ from the shadow
+fn for_each_run<F: FnMut(u32, u32)>(top: u32, words: u32, mut f: F) {
+    let old_words = unsafe { (*addr_of!(shadow_tag)).words };
+    let changed = |i: u32| i >= old_words || unsafe { ptr::read_volatile(shadow(i)) } != stack_word(top, i);
+    let mut i = 0;
+    while i < words {
+        if !changed(i) {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < words && i - start < MAX_RUN && changed(i) {
+            i += 1;
+        }
+        f(start, i - start);
+    }
+}
+
+pub fn delta_body_len(top: u32, words: u32) -> u32 {
+    let mut len = 0;
+    for_each_run(top, words, |_, count| len += 4 + count * 4);
+    len
+}
+
+// Writes the runs at `address` and updates the shadow as it goes, returns the
+// address after the last run and the updated crc.
+pub fn write_delta(flash: &mut FLASH, mut address: u32, top: u32, words: u32, mut crc: u32) -> (u32, u32) {
+    invalidate_shadow();
+    for_each_run(top, words, |start, count| {
+        let run = start << 16 | count;
+        write_to_flash(flash, address, run);
+        crc = crc32_update(crc, run);
+        address += 4;
+        for i in start..start + count {
+            let word = stack_word(top, i);
+            write_to_flash(flash, address, word);
+            crc = crc32_update(crc, word);
+            unsafe { ptr::write_volatile(shadow(i), word) };
+            address += 4;
+        }
+    });
+    (address, crc)
+}
+
+// the shadow no longer matches any committed frame until set_shadow()
+pub fn invalidate_shadow() {
+    unsafe { (*addr_of_mut!(shadow_tag)).valid = 0 };
+}
+
+// word `index` of a full frame's stack image, written next to the flash copy
+pub fn record_shadow(index: u32, word: u32) {
+    if index < shadow_capacity() {
+        unsafe { ptr::write_volatile(shadow(index), word) };
+    }
+}
+
+// copy of the whole stack image, for frames that are not written word by word
+pub fn copy_stack_to_shadow(top: u32, words: u32) {
+    for i in 0..words {
+        record_shadow(i, stack_word(top, i));
+    }
+}
+
+// call after `header` is committed and the shadow holds its stack image
+pub fn set_shadow(header: &FrameHeader) {
+    let words = header.stack_len / 4;
+    if words > shadow_capacity() {
+        return;
+    }
+    unsafe {
+        let tag = &mut *addr_of_mut!(shadow_tag);
+        tag.seq = header.seq;
+        tag.base_seq = header.base_seq;
+        tag.words = words;
+        tag.valid = SHADOW_VALID;
+    }
+}
+
+fn apply_runs(address: u32, header: &FrameHeader) -> bool {
+    let words = header.stack_len / 4;
+    let mut offset = HEADER_SIZE;
+    let end = HEADER_SIZE + header.body_len();
+    while offset < end {
+        let run = unsafe { ptr::read_volatile((address + offset) as *const u32) };
+        let (start, count) = (run >> 16, run & MAX_RUN);
+        if start + count > words || offset + 4 + count * 4 > end {
+            return false;
+        }
+        for i in 0..count {
+            let word = unsafe { ptr::read_volatile((address + offset + 4 + i * 4) as *const u32) };
+            unsafe { ptr::write_volatile(shadow(start + i), word) };
+        }
+        offset += 4 + count * 4;
+    }
+    true
+}
+
+// Rebuilds the stack image of the committed frame at `address` in the shadow,
+// for a delta frame from its base and every committed delta in between.
+// Returns false if the chain is incomplete or any part of it is corrupt.
+pub fn rebuild_shadow(address: u32, header: &FrameHeader) -> bool {
+    if header.stack_len / 4 > shadow_capacity() {
+        return false;
+    }
+    invalidate_shadow();
+
+    let (base_address, base) = if header.is_delta() {
+        let base = FrameIter::new(bank_of(address))
+            .find(|(a, h)| h.seq == header.base_seq && !h.is_delta() && h.is_intact(*a));
+        match base {
+            Some(base) => base,
+            None => return false,
+        }
+    } else {
+        (address, *header)
+    };
+
+    let words = base.stack_len / 4;
+    match base.encoding() {
+        ENCODING_RAW => {
+            for i in 0..words {
+                let word = unsafe { ptr::read_volatile((base_address + HEADER_SIZE + i * 4) as *const u32) };
+                unsafe { ptr::write_volatile(shadow(i), word) };
+            }
+        }
+        ENCODING_WORD_RLE => {
+            if !decompress(base_address + HEADER_SIZE, base.body_len(), stack_shadow_start(), words) {
+                return false;
+            }
+        }
+        _ => return false,
+    }
+
+    // every committed delta up to this frame has to be there, one that has
+    // rotted leaves a hole the later deltas do not cover
+    if header.is_delta() {
+        let chain = FrameIter::new(bank_of(address)).filter(|(_, h)| {
+            h.is_delta() && h.base_seq == header.base_seq && h.seq <= header.seq && h.is_valid() && h.is_committed()
+        });
+        for (a, h) in chain {
+            if !h.is_intact(a) || !apply_runs(a, &h) {
+                return false;
+            }
+        }
+    }
+
+    set_shadow(header);
+    true
+}
@@ -0,0 +1,125 @@
+// What checkpoint() returns. save_frame() hands the result back through
+// __checkpoint_capture as a status word, 0 is success; after a restore
+// checkpoint() returns a second time with STATUS_RESUMED | restores instead.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckpointStatus {
+    // the frame was written, execution goes on as usual
+    Checkpointed,
+    // restore() brought this checkpoint back, `restores` times so far
+    Resumed { restores: u32 },
+}
+
+// Why checkpoint() did not write a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckpointError {
+    // sp is below _stack_end, the stack has overflowed and is not worth keeping
+    StackOverflow,
+    // sp is not inside the main stack at all (above _stack_start, or the
+    // caller runs on some other stack)
+    StackOutOfRange,
+    // the frame does not fit into a bank
+    FrameTooLarge,
+    // a checkpoint, erase or gc step is writing flash already (a handler
+    // interrupted it), nothing was written
+    Busy,
+    // called from a handler, a frame taken in Handler mode cannot be resumed
+    HandlerMode,
+}
+
+pub const STATUS_OK: u32 = 0;
+pub const STATUS_RESUMED: u32 = 1 << 31;
+
+impl CheckpointError {
+    pub fn status(self) -> u32 {
+        match self {
+            CheckpointError::StackOverflow => 1,
+            CheckpointError::StackOutOfRange => 2,
+            CheckpointError::FrameTooLarge => 3,
+            CheckpointError::Busy => 4,
+            CheckpointError::HandlerMode => 5,
+        }
+    }
+}
+
+pub fn decode_status(status: u32) -> Result<CheckpointStatus, CheckpointError> {
+    match status {
+        STATUS_OK => Ok(CheckpointStatus::Checkpointed),
+        1 => Err(CheckpointError::StackOverflow),
+        2 => Err(CheckpointError::StackOutOfRange),
+        3 => Err(CheckpointError::FrameTooLarge),
+        4 => Err(CheckpointError::Busy),
+        5 => Err(CheckpointError::HandlerMode),
+        _ => Ok(CheckpointStatus::Resumed { restores: status & !STATUS_RESUMED }),
+    }
+}
+
+// status word a resumed checkpoint() returns
+pub fn resumed_status(restores: u32) -> u32 {
+    STATUS_RESUMED | restores.min(!STATUS_RESUMED)
+}
+
+// Why restore() did not resume a frame; main() cold boots instead. The code
+// of the last failure is kept in F-RAM (see last_restore_error()).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestoreError {
+    // no committed frame (or no frame with the requested seq)
+    NoFrame,
+    // header from another firmware version, or inconsistent sizes
+    BadHeader,
+    // not committed, or the crc does not match
+    Corrupt,
+    // the saved sp is outside the main stack or a registered task stack
+    BadStackPointer,
+    // the stack image does not cover sp up to _stack_start
+    StackLengthMismatch,
+    // the saved pc is not in .text or not a Thumb address, or the frame was
+    // taken in Handler mode
+    BadProgramCounter,
+    // the delta chain or the encoded image cannot be rebuilt
+    BrokenChain,
+    // a task stack of the frame is not registered
+    TaskStacks,
+    // the frame's .data/.bss section is from a different firmware
+    Globals,
+    // the newest frame keeps getting restored without a newer one being
+    // committed and no older frame is left to fall back to, see loops.rs
+    RestoreLoop,
+    // the frame's heap section is not for the heap registered now
+    Heap,
+}
+
+impl RestoreError {
+    pub fn code(self) -> u32 {
+        match self {
+            RestoreError::NoFrame => 1,
+            RestoreError::BadHeader => 2,
+            RestoreError::Corrupt => 3,
+            RestoreError::BadStackPointer => 4,
+            RestoreError::StackLengthMismatch => 5,
+            RestoreError::BadProgramCounter => 6,
+            RestoreError::BrokenChain => 7,
+            RestoreError::TaskStacks => 8,
+            RestoreError::Globals => 9,
+            RestoreError::RestoreLoop => 10,
+            RestoreError::Heap => 11,
+        }
+    }
+
+    pub fn from_code(code: u32) -> Option<RestoreError> {
+        match code {
+            1 => Some(RestoreError::NoFrame),
+            2 => Some(RestoreError::BadHeader),
+            3 => Some(RestoreError::Corrupt),
+            4 => Some(RestoreError::BadStackPointer),
+            5 => Some(RestoreError::StackLengthMismatch),
+            6 => Some(RestoreError::BadProgramCounter),
+            7 => Some(RestoreError::BrokenChain),
+            8 => Some(RestoreError::TaskStacks),
+            9 => Some(RestoreError::Globals),
+            10 => Some(RestoreError::RestoreLoop),
+            11 => Some(RestoreError::Heap),
+            _ => None,
+        }
+    }
+}
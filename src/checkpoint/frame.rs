@@ -0,0 +1,359 @@
+#![allow(unsafe_code)]
+use core::ptr;
+use stm32f3xx_hal_v2::pac::FLASH;
+
+use super::crc::{crc32_flash, crc32_update};
+use super::my_flash::write_to_flash;
+
+// Every checkpoint frame in flash starts with this header, followed by the
+// stack image, the end of stack marker, the register block and a CRC-32 over
+// everything between the header and the CRC word.
+//
+//  0x00  total_len   size of the whole frame, used to walk the log
+//  0x04  magic       FRAME_MAGIC
+//  0x08  version     FRAME_VERSION (low half word) | flags (high half word)
+//  0x0C  seq         sequence number, increases with every checkpoint
+//  0x10  stack_len   size of the stack image in bytes
+//  0x14  reg_offset  offset of the register block from the start of the frame
+//  0x18  commit      left erased while the frame is written, COMMIT_MARKER once
+//                    the body and crc are in flash
+//  0x1C  base_seq    full frame a delta frame applies to, seq for full frames
+//
+// A full frame stores the stack image (stack_len bytes) after the header,
+// encoded as given by the encoding bits of the flags (see compress.rs). A
+// delta frame (FLAG_DELTA) stores the changed runs instead (see delta.rs).
+// Either way the body length is reg_offset - HEADER_SIZE - 4.
+//
+// The register block holds r0 - r15 and the special registers, followed by
+// S0 - S31 and FPSCR for frames taken with the FPU enabled (FLAG_FPU) and the
+// RAM globals (FLAG_GLOBALS, see globals.rs), the heap (FLAG_HEAP, see
+// heap.rs) and the task stacks (FLAG_TASKS, see tasks.rs). The crc is always
+// the last word.
+//
+// `total_len` stays the first word so an erased word (0xffff_ffff) still marks
+// the end of the log. Bump FRAME_VERSION whenever the layout changes, restore()
+// ignores frames written with any other version.
+//
+// A frame is written in two phases: header (without commit) and body first,
+// then the commit word. The commit word is erased until then, so it can be
+// programmed last and an interrupted checkpoint is never committed.
+
+pub const FRAME_MAGIC: u32 = 0xC4EC_4B01;
+pub const FRAME_VERSION: u16 = 13;
+pub const HEADER_SIZE: u32 = 32;
+pub const COMMIT_MARKER: u32 = 0x5A5A_C0DE;
+pub const COMMIT_OFFSET: u32 = 0x18;
+
+// 0xf1f1_f1f1 (end of stack in the frame magic number)
+pub const STACK_END_MARKER: u32 = 0xf1f1_f1f1;
+// r0 - r15, xPSR, PRIMASK, BASEPRI, FAULTMASK, CONTROL, MSP, PSP, see context.rs
+pub const REGISTER_BLOCK_SIZE: u32 = 23 * 4;
+// S0 - S31, FPSCR
+pub const FPU_BLOCK_SIZE: u32 = 33 * 4;
+pub const CRC_SIZE: u32 = 4;
+
+// jit checkpoint, the transaction log has to be rolled back on restore
+pub const FLAG_JIT: u16 = 1 << 0;
+// body holds the runs changed since the previous frame instead of the stack image
+pub const FLAG_DELTA: u16 = 1 << 1;
+// the register block carries the FPU registers
+pub const FLAG_FPU: u16 = 1 << 2;
+// taken by checkpoint_from_exception() or PendSV, r0 is the interrupted
+// thread's r0 rather than checkpoint()'s return value
+pub const FLAG_EXCEPTION: u16 = 1 << 3;
+// a task section follows the register block
+pub const FLAG_TASKS: u16 = 1 << 4;
+// a .data/.bss section follows the register block, before the task section
+pub const FLAG_GLOBALS: u16 = 1 << 5;
+// a heap section follows the globals section, before the task section
+pub const FLAG_HEAP: u16 = 1 << 6;
+
+// stack image encoding, bits 8..11 of the flags
+const ENCODING_SHIFT: u16 = 8;
+const ENCODING_MASK: u16 = 0xf << ENCODING_SHIFT;
+pub const ENCODING_RAW: u16 = 0;
+pub const ENCODING_WORD_RLE: u16 = 1;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct FrameHeader {
+    pub total_len: u32,
+    pub magic: u32,
+    pub version: u16,
+    pub flags: u16,
+    pub seq: u32,
+    pub stack_len: u32,
+    pub reg_offset: u32,
+    pub commit: u32,
+    pub base_seq: u32,
+}
+
+impl FrameHeader {
+    pub fn new(seq: u32, flags: u16, stack_len: u32) -> FrameHeader {
+        FrameHeader::with_body(seq, seq, flags & !FLAG_DELTA, stack_len, stack_len)
+    }
+
+    // full frame with the stack image stored in `encoding`, `body_len` bytes
+    pub fn encoded(seq: u32, flags: u16, encoding: u16, stack_len: u32, body_len: u32) -> FrameHeader {
+        let flags = flags & !(FLAG_DELTA | ENCODING_MASK) | encoding << ENCODING_SHIFT;
+        FrameHeader::with_body(seq, seq, flags, stack_len, body_len)
+    }
+
+    // delta frame on top of the chain starting at full frame `base_seq`
+    pub fn delta(seq: u32, base_seq: u32, flags: u16, stack_len: u32, body_len: u32) -> FrameHeader {
+        FrameHeader::with_body(seq, base_seq, flags | FLAG_DELTA, stack_len, body_len)
+    }
+
+    fn with_body(seq: u32, base_seq: u32, flags: u16, stack_len: u32, body_len: u32) -> FrameHeader {
+        let reg_offset = HEADER_SIZE + body_len + 4;
+        FrameHeader {
+            total_len: reg_offset + register_block_size(flags) + CRC_SIZE,
+            magic: FRAME_MAGIC,
+            version: FRAME_VERSION,
+            flags,
+            seq,
+            stack_len,
+            reg_offset,
+            commit: 0xffff_ffff,
+            base_seq,
+        }
+    }
+
+    // adds a globals section of `len` bytes
+    pub fn with_globals(mut self, len: u32) -> FrameHeader {
+        self.flags |= FLAG_GLOBALS;
+        self.total_len += len;
+        self
+    }
+
+    // adds a heap section of `len` bytes
+    pub fn with_heap(mut self, len: u32) -> FrameHeader {
+        self.flags |= FLAG_HEAP;
+        self.total_len += len;
+        self
+    }
+
+    // adds a task section of `len` bytes
+    pub fn with_tasks(mut self, len: u32) -> FrameHeader {
+        self.flags |= FLAG_TASKS;
+        self.total_len += len;
+        self
+    }
+
+    pub fn read(addr: u32) -> FrameHeader {
+        unsafe {
+            let word = |offset: u32| ptr::read_volatile((addr + offset) as *const u32);
+            let version_flags = word(0x08);
+            FrameHeader {
+                total_len: word(0x00),
+                magic: word(0x04),
+                version: version_flags as u16,
+                flags: (version_flags >> 16) as u16,
+                seq: word(0x0C),
+                stack_len: word(0x10),
+                reg_offset: word(0x14),
+                commit: word(COMMIT_OFFSET),
+                base_seq: word(0x1C),
+            }
+        }
+    }
+
+    pub fn write(&self, flash: &mut FLASH, addr: u32) {
+        write_to_flash(flash, addr, self.total_len);
+        write_to_flash(flash, addr + 0x04, self.magic);
+        write_to_flash(flash, addr + 0x08, self.version as u32 | (self.flags as u32) << 16);
+        write_to_flash(flash, addr + 0x0C, self.seq);
+        write_to_flash(flash, addr + 0x10, self.stack_len);
+        write_to_flash(flash, addr + 0x14, self.reg_offset);
+        write_to_flash(flash, addr + 0x1C, self.base_seq);
+    }
+
+    // second phase, call only after the whole frame including the crc is written
+    pub fn commit(flash: &mut FLASH, addr: u32) {
+        write_to_flash(flash, addr + COMMIT_OFFSET, COMMIT_MARKER);
+    }
+
+    pub fn is_committed(&self) -> bool {
+        self.commit == COMMIT_MARKER
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.magic == FRAME_MAGIC
+            && self.version == FRAME_VERSION
+            && self.body_ok()
+            && self.size_ok()
+    }
+
+    // Sizes are added with checked_add: a header torn after total_len, magic
+    // and version still has the other fields erased (0xffff_ffff), and an
+    // overflow has to make it invalid, not panic.
+    fn size_ok(&self) -> bool {
+        let fixed = match self.reg_offset.checked_add(register_block_size(self.flags) + CRC_SIZE) {
+            Some(fixed) => fixed,
+            None => return false,
+        };
+        if self.has_tasks() || self.has_globals() || self.has_heap() {
+            fixed.checked_add(4).is_some_and(|min| self.total_len >= min) && self.total_len.is_multiple_of(4)
+        } else {
+            self.total_len == fixed
+        }
+    }
+
+    fn body_ok(&self) -> bool {
+        let sized = self.reg_offset >= HEADER_SIZE + 4 && self.reg_offset.is_multiple_of(4);
+        if self.is_delta() {
+            sized && self.encoding() == ENCODING_RAW && self.base_seq < self.seq
+        } else {
+            let body = match self.encoding() {
+                ENCODING_RAW => self.stack_len.checked_add(HEADER_SIZE + 4) == Some(self.reg_offset),
+                ENCODING_WORD_RLE => sized,
+                _ => false,
+            };
+            body && self.base_seq == self.seq
+        }
+    }
+
+    pub fn body_len(&self) -> u32 {
+        self.reg_offset - HEADER_SIZE - 4
+    }
+
+    pub fn crc_offset(&self) -> u32 {
+        self.total_len - CRC_SIZE
+    }
+
+    // offset of the task section, it runs up to the crc
+    pub fn tasks_offset(&self, addr: u32) -> u32 {
+        match self.heap_offset(addr) {
+            Some(offset) if offset < self.crc_offset() => offset.saturating_add(self.word(addr, offset)),
+            Some(offset) => offset,
+            None => self.globals_end(addr),
+        }
+    }
+
+    // offset of the heap section, its first word is its length
+    pub fn heap_offset(&self, addr: u32) -> Option<u32> {
+        if self.has_heap() {
+            Some(self.globals_end(addr))
+        } else {
+            None
+        }
+    }
+
+    // end of the register block, or of the globals section after it
+    fn globals_end(&self, addr: u32) -> u32 {
+        match self.globals_offset() {
+            Some(offset) => offset.saturating_add(self.word(addr, offset)),
+            None => self.reg_offset + register_block_size(self.flags),
+        }
+    }
+
+    fn word(&self, addr: u32, offset: u32) -> u32 {
+        unsafe { ptr::read_volatile((addr + offset) as *const u32) }
+    }
+
+    // offset of the globals section, its first word is its length
+    pub fn globals_offset(&self) -> Option<u32> {
+        if self.has_globals() {
+            Some(self.reg_offset + register_block_size(self.flags))
+        } else {
+            None
+        }
+    }
+
+    // header is sane, the frame was committed and the stored crc matches the
+    // stack image and registers, a frame torn by a power failure fails this check
+    pub fn is_intact(&self, addr: u32) -> bool {
+        if !self.is_valid() || !self.is_committed() {
+            return false;
+        }
+        let stored = unsafe { ptr::read_volatile((addr + self.crc_offset()) as *const u32) };
+        stored == crc32_flash(addr + HEADER_SIZE, self.crc_offset() - HEADER_SIZE)
+    }
+
+    // saved register rN of the frame at `addr`
+    pub fn register(&self, addr: u32, n: u32) -> u32 {
+        unsafe { ptr::read_volatile((addr + self.reg_offset + n * 4) as *const u32) }
+    }
+
+    pub fn is_jit(&self) -> bool {
+        self.flags & FLAG_JIT != 0
+    }
+
+    pub fn is_delta(&self) -> bool {
+        self.flags & FLAG_DELTA != 0
+    }
+
+    pub fn is_exception(&self) -> bool {
+        self.flags & FLAG_EXCEPTION != 0
+    }
+
+    pub fn has_tasks(&self) -> bool {
+        self.flags & FLAG_TASKS != 0
+    }
+
+    pub fn has_globals(&self) -> bool {
+        self.flags & FLAG_GLOBALS != 0
+    }
+
+    pub fn has_heap(&self) -> bool {
+        self.flags & FLAG_HEAP != 0
+    }
+
+    pub fn has_fpu(&self) -> bool {
+        self.flags & FLAG_FPU != 0
+    }
+
+    // address of S0 - S31 and FPSCR in the frame at `addr`, None if the frame
+    // was taken with the FPU disabled
+    pub fn fpu_registers(&self, addr: u32) -> Option<u32> {
+        if self.has_fpu() {
+            Some(addr + self.reg_offset + REGISTER_BLOCK_SIZE)
+        } else {
+            None
+        }
+    }
+
+    pub fn encoding(&self) -> u16 {
+        (self.flags & ENCODING_MASK) >> ENCODING_SHIFT
+    }
+}
+
+// Appends the optional sections (globals.rs, heap.rs, tasks.rs) to a frame
+// being written, word by word, keeping the frame's running crc.
+pub struct SectionWriter<'a> {
+    flash: &'a mut FLASH,
+    address: u32,
+    crc: u32,
+}
+
+impl<'a> SectionWriter<'a> {
+    pub fn new(flash: &'a mut FLASH, address: u32, crc: u32) -> SectionWriter<'a> {
+        SectionWriter { flash, address, crc }
+    }
+
+    pub fn word(&mut self, word: u32) {
+        write_to_flash(self.flash, self.address, word);
+        self.crc = crc32_update(self.crc, word);
+        self.address += 4;
+    }
+
+    // `len` bytes of memory from `start`, whole words
+    pub fn copy(&mut self, start: u32, len: u32) {
+        for i in 0..len / 4 {
+            self.word(unsafe { ptr::read_volatile((start + i * 4) as *const u32) });
+        }
+    }
+
+    // address after the last word written and the crc so far
+    pub fn finish(self) -> (u32, u32) {
+        (self.address, self.crc)
+    }
+}
+
+fn register_block_size(flags: u16) -> u32 {
+    if flags & FLAG_FPU != 0 {
+        REGISTER_BLOCK_SIZE + FPU_BLOCK_SIZE
+    } else {
+        REGISTER_BLOCK_SIZE
+    }
+}
@@ -0,0 +1,84 @@
+#![allow(unsafe_code)]
+use core::ptr;
+use stm32f3xx_hal_v2::pac::FLASH;
+
+use super::bank::{scan_log, bank_start, bank_size, dirty_page, FrameIter, BANK_COUNT};
+use super::frame::{FrameHeader, COMMIT_OFFSET};
+use super::history::Needed;
+use super::my_flash::write_to_flash;
+use super::wear::erase_counted;
+use super::{claim_flash, release_flash};
+
+// Incremental garbage collection of the checkpoint area.
+//
+// gc_step() does one bounded piece of work, either erasing one page of a bank
+// that only holds superseded frames or copying one needed frame (see
+// history::Needed) from an older bank forward into the active bank, and
+// returns true while there is more to do, so it can be called from an idle
+// loop. A copy is committed only after it is complete and the original is
+// erased only once the copy is committed, so there is always at least one
+// valid checkpoint in flash.
+//
+// A step that finds a checkpoint or erase running does nothing and returns
+// false, gc() called from a handler would otherwise spin on it forever.
+
+pub fn gc_step(flash: &mut FLASH) -> bool {
+    if !claim_flash() {
+        return false;
+    }
+    let more = collect(flash);
+    release_flash();
+    more
+}
+
+fn collect(flash: &mut FLASH) -> bool {
+    let log = scan_log();
+    let needed = Needed::scan();
+
+    // 1. reclaim pages of banks nothing needs anymore
+    for bank in (0..BANK_COUNT).filter(|bank| *bank != log.active_bank) {
+        if needed.bank_is_live(bank) {
+            continue;
+        }
+        if let Some(page) = dirty_page(bank) {
+            erase_counted(flash, page);
+            return true;
+        }
+    }
+
+    // 2. move needed frames out of the older banks, in bank order so a delta
+    //    chain's base is copied before its deltas
+    let active = 1 << log.active_bank;
+    for bank in (0..BANK_COUNT).filter(|bank| *bank != log.active_bank) {
+        let frame = FrameIter::new(bank).find(|(address, header)| {
+            needed.needs(header) && needed.copies(header.seq) & active == 0 && header.is_intact(*address)
+        });
+        if let Some((address, header)) = frame {
+            if header.total_len > bank_start(log.active_bank) + bank_size() - log.tail {
+                // no room, the next bank switch makes room
+                return false;
+            }
+            copy_frame(flash, address, log.tail, &header);
+            return true;
+        }
+    }
+    false
+}
+
+// run gc_step() until there is nothing left to do
+pub fn gc(flash: &mut FLASH) {
+    while gc_step(flash) {}
+}
+
+// Copy a committed frame word by word, the copy is committed last.
+fn copy_frame(flash: &mut FLASH, from: u32, to: u32, header: &FrameHeader) {
+    let mut offset = 0;
+    while offset < header.total_len {
+        let word = unsafe { ptr::read_volatile((from + offset) as *const u32) };
+        if offset != COMMIT_OFFSET && word != 0xffff_ffff {
+            write_to_flash(flash, to + offset, word);
+        }
+        offset += 4;
+    }
+    FrameHeader::commit(flash, to);
+}
@@ -0,0 +1,62 @@
+#![allow(unsafe_code)]
+use core::ptr;
+
+use super::frame::SectionWriter;
+use super::layout::{data_start, data_end, bss_start, bss_end};
+
+// RAM globals in a frame (FLAG_GLOBALS), for applications whose statics
+// have to survive a restore along with the stack. Only written while
+// checkpoint_globals is set. Globals section, after the register block:
+//
+//   len (bytes, including this header), data start, data len, bss start,
+//   bss len, the .data words, the .bss words
+//
+// restore() only takes the section back if both ranges match the running
+// firmware's, anything else would scatter the words over the wrong statics.
+
+const SECTION_HEADER: u32 = 5 * 4;
+
+fn ranges() -> [(u32, u32); 2] {
+    [(data_start(), data_end() - data_start()), (bss_start(), bss_end() - bss_start())]
+}
+
+pub fn globals_len() -> u32 {
+    SECTION_HEADER + ranges().iter().map(|(_, len)| len).sum::<u32>()
+}
+
+pub fn write_globals(writer: &mut SectionWriter) {
+    writer.word(globals_len());
+    for (start, len) in ranges() {
+        writer.word(start);
+        writer.word(len);
+    }
+    for (start, len) in ranges() {
+        writer.copy(start, len);
+    }
+}
+
+// the section at `address` was written by this firmware and is `len` bytes
+pub fn globals_match(address: u32, len: u32) -> bool {
+    let read = |offset: u32| unsafe { ptr::read_volatile((address + offset) as *const u32) };
+    if len != globals_len() || read(0) != len {
+        return false;
+    }
+    ranges()
+        .iter()
+        .enumerate()
+        .all(|(i, (start, range_len))| read(4 + i as u32 * 8) == *start && read(8 + i as u32 * 8) == *range_len)
+}
+
+// Copies .data and .bss back from the section at `address`, check it with
+// globals_match() first. Every static changes under the caller's feet,
+// including the ones of this module's callers.
+pub fn restore_ram_globals(address: u32) {
+    let mut offset = SECTION_HEADER;
+    for (start, len) in ranges() {
+        for i in 0..len / 4 {
+            let word = unsafe { ptr::read_volatile((address + offset + i * 4) as *const u32) };
+            unsafe { ptr::write_volatile((start + i * 4) as *mut u32, word) };
+        }
+        offset += len;
+    }
+}
@@ -0,0 +1,182 @@
+#![allow(unsafe_code, non_upper_case_globals)]
+use core::ptr::{self, addr_of, addr_of_mut};
+
+use super::frame::SectionWriter;
+use super::layout::{stack_start, stack_end};
+
+// Heap of an application with a global allocator (alloc-cortex-m, see
+// examples/allocator.rs). The arena and the allocator's own state, the
+// CortexMHeap static with the free list head, are registered once the
+// allocator is initialised; every frame then carries them, in the same frame
+// and under the same crc as the stack, so Vec and Box contents come back
+// together with the pointers to them. CortexMHeap allocates inside a critical
+// section, a checkpoint from an interrupt never sees it half way.
+//
+// The whole arena is saved unless the heap comes with `live_ranges`, which
+// fills the buffer with the (start, len) ranges to keep and returns how many
+// there are: the allocated blocks and whatever the allocator keeps inside
+// free blocks (the hole headers of a linked list allocator). A callback that
+// returns more than MAX_HEAP_RANGES or a range outside the arena gets the
+// whole arena saved instead.
+//
+// Heap section of a frame (FLAG_HEAP), after the globals section:
+//
+//   len (bytes, including this header), arena start, arena size, state
+//   start, state len, range count, the state words, then per range: start,
+//   len, the words
+//
+// restore() only takes the section back if it was written for the heap
+// registered now.
+
+pub const MAX_HEAP_RANGES: usize = 16;
+
+const SECTION_HEADER: u32 = 6 * 4;
+
+// fills the buffer with (start, len) ranges of the arena, returns how many
+pub type LiveRanges = fn(&mut [(u32, u32)]) -> usize;
+
+#[derive(Clone, Copy)]
+pub struct Heap {
+    pub start: u32,
+    pub size: u32,
+    // allocator state outside the arena
+    pub state: u32,
+    pub state_len: u32,
+    pub live_ranges: Option<LiveRanges>,
+}
+
+static mut registered_heap: Option<Heap> = None;
+
+// Registers the heap arena and the allocator state, replacing the heap
+// registered before. Returns false for ranges that are not word aligned or
+// overlap the main stack.
+pub fn register_heap(start: u32, size: u32, state: u32, state_len: u32, live_ranges: Option<LiveRanges>) -> bool {
+    let aligned = [start, size, state, state_len].iter().all(|v| v.is_multiple_of(4));
+    let overlaps = |s: u32, len: u32| s < stack_start() && stack_end() < s.saturating_add(len);
+    if size == 0 || !aligned || overlaps(start, size) || overlaps(state, state_len) {
+        return false;
+    }
+    unsafe { *addr_of_mut!(registered_heap) = Some(Heap { start, size, state, state_len, live_ranges }) };
+    true
+}
+
+pub fn unregister_heap() {
+    unsafe { *addr_of_mut!(registered_heap) = None };
+}
+
+fn registered() -> Option<Heap> {
+    unsafe { *addr_of!(registered_heap) }
+}
+
+// Registered heap and its live ranges as of the start of a checkpoint, the
+// frame's size is planned from this and the section written from it.
+pub struct HeapSnapshot {
+    heap: Option<Heap>,
+    ranges: [(u32, u32); MAX_HEAP_RANGES],
+    count: usize,
+}
+
+impl HeapSnapshot {
+    pub fn take() -> HeapSnapshot {
+        let mut snapshot = HeapSnapshot { heap: registered(), ranges: [(0, 0); MAX_HEAP_RANGES], count: 0 };
+        let heap = match snapshot.heap {
+            Some(heap) => heap,
+            None => return snapshot,
+        };
+        if let Some(live_ranges) = heap.live_ranges {
+            let count = live_ranges(&mut snapshot.ranges);
+            if count <= MAX_HEAP_RANGES {
+                snapshot.count = count;
+            }
+            // whole words, and each range inside the arena
+            for range in snapshot.ranges[..snapshot.count].iter_mut() {
+                let (start, end) = (range.0 & !3, range.0.saturating_add(range.1).saturating_add(3) & !3);
+                if start < heap.start || end > heap.start + heap.size || end < start {
+                    snapshot.count = 0;
+                    break;
+                }
+                *range = (start, end - start);
+            }
+        }
+        if snapshot.count == 0 {
+            snapshot.ranges[0] = (heap.start, heap.size);
+            snapshot.count = 1;
+        }
+        snapshot
+    }
+
+    // size of the heap section in bytes, 0 without a registered heap
+    pub fn section_len(&self) -> u32 {
+        match self.heap {
+            Some(heap) => SECTION_HEADER + heap.state_len + self.ranges[..self.count].iter().map(|(_, len)| 8 + len).sum::<u32>(),
+            None => 0,
+        }
+    }
+
+    pub fn write(&self, writer: &mut SectionWriter) {
+        let heap = match self.heap {
+            Some(heap) => heap,
+            None => return,
+        };
+        writer.word(self.section_len());
+        writer.word(heap.start);
+        writer.word(heap.size);
+        writer.word(heap.state);
+        writer.word(heap.state_len);
+        writer.word(self.count as u32);
+        writer.copy(heap.state, heap.state_len);
+        for (start, len) in self.ranges[..self.count].iter() {
+            writer.word(*start);
+            writer.word(*len);
+            writer.copy(*start, *len);
+        }
+    }
+}
+
+// the section at `address` is `len` bytes and was written for the heap
+// registered now
+pub fn heap_match(address: u32, len: u32) -> bool {
+    let read = |offset: u32| unsafe { ptr::read_volatile((address + offset) as *const u32) };
+    let heap = match registered() {
+        Some(heap) => heap,
+        None => return false,
+    };
+    if len < SECTION_HEADER || read(0) != len {
+        return false;
+    }
+    if read(4) != heap.start || read(8) != heap.size || read(12) != heap.state || read(16) != heap.state_len {
+        return false;
+    }
+    let mut offset = SECTION_HEADER + heap.state_len;
+    for _ in 0..read(20) {
+        if offset.saturating_add(8) > len {
+            return false;
+        }
+        let (start, range_len) = (read(offset), read(offset + 4));
+        let inside = start >= heap.start && range_len <= heap.size && start - heap.start <= heap.size - range_len;
+        if !inside || !start.is_multiple_of(4) || !range_len.is_multiple_of(4) || offset + 8 + range_len > len {
+            return false;
+        }
+        offset += 8 + range_len;
+    }
+    offset == len
+}
+
+// Copies the allocator state and the saved ranges back from the section at
+// `address`, check it with heap_match() first.
+pub fn restore_heap(address: u32) {
+    let read = |offset: u32| unsafe { ptr::read_volatile((address + offset) as *const u32) };
+    let copy = |offset: u32, start: u32, len: u32| {
+        for i in 0..len / 4 {
+            unsafe { ptr::write_volatile((start + i * 4) as *mut u32, read(offset + i * 4)) };
+        }
+    };
+    let state_len = read(16);
+    copy(SECTION_HEADER, read(12), state_len);
+    let mut offset = SECTION_HEADER + state_len;
+    for _ in 0..read(20) {
+        let (start, len) = (read(offset), read(offset + 4));
+        copy(offset + 8, start, len);
+        offset += 8 + len;
+    }
+}
@@ -0,0 +1,146 @@
+use super::bank::{FrameIter, BANK_COUNT, bank_of};
+use super::delta::DELTA_CHAIN_MAX;
+use super::frame::FrameHeader;
+
+// Committed frames still on flash. The newest retain_frames frames (see
+// mod.rs, at most MAX_RETAINED_FRAMES) are kept: a bank holding the only copy
+// of one of them (or of a frame in its delta chain) is live, it is neither
+// retired nor picked for reuse while another bank is available (see bank.rs),
+// so restore_frame() can go back to any of them. gc.rs copies live frames
+// forward so their old banks can be reclaimed. Lowering retain_frames frees
+// banks at the next bank switch, raising it cannot bring back frames that are
+// already erased.
+
+pub const MAX_RETAINED_FRAMES: usize = 8;
+// every retained frame needs at most its base and DELTA_CHAIN_MAX deltas
+const MAX_NEEDED: usize = MAX_RETAINED_FRAMES * (DELTA_CHAIN_MAX as usize + 1);
+
+// retain_frames, capped, at least the newest frame
+pub fn retained_count() -> usize {
+    unsafe { super::retain_frames }.clamp(1, MAX_RETAINED_FRAMES)
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct FrameInfo {
+    pub address: u32,
+    pub seq: u32,
+    // full frame the frame's delta chain starts from, seq for full frames
+    pub base_seq: u32,
+    pub jit: bool,
+    pub delta: bool,
+    // size of the frame in flash
+    pub size: u32,
+    // bytes of stack saved
+    pub stack_depth: u32,
+    pub pc: u32,
+}
+
+impl FrameInfo {
+    fn new(address: u32, header: &FrameHeader) -> FrameInfo {
+        FrameInfo {
+            address,
+            seq: header.seq,
+            base_seq: header.base_seq,
+            jit: header.is_jit(),
+            delta: header.is_delta(),
+            size: header.total_len,
+            stack_depth: header.stack_len,
+            pc: header.register(address, 15),
+        }
+    }
+}
+
+pub fn committed() -> impl Iterator<Item = (u32, FrameHeader)> {
+    (0..BANK_COUNT).flat_map(FrameIter::new).filter(|(address, header)| header.is_intact(*address))
+}
+
+// committed frames by their headers only, without the crc check
+fn committed_headers() -> impl Iterator<Item = (u32, FrameHeader)> {
+    (0..BANK_COUNT).flat_map(FrameIter::new).filter(|(_, header)| header.is_valid() && header.is_committed())
+}
+
+// Fills `out` with the newest committed frames, newest first, and returns how
+// many were found.
+pub fn list_frames(out: &mut [FrameInfo]) -> usize {
+    newest_of(committed(), out)
+}
+
+fn newest_of(frames: impl Iterator<Item = (u32, FrameHeader)>, out: &mut [FrameInfo]) -> usize {
+    let mut found = 0;
+    for (address, header) in frames {
+        // insertion into the sorted prefix, the oldest falls off the end
+        let mut i = found;
+        while i > 0 && out[i - 1].seq < header.seq {
+            if i < out.len() {
+                out[i] = out[i - 1];
+            }
+            i -= 1;
+        }
+        if i < out.len() {
+            out[i] = FrameInfo::new(address, &header);
+        }
+        if found < out.len() {
+            found += 1;
+        }
+    }
+    found
+}
+
+pub fn find_frame(seq: u32) -> Option<(u32, FrameHeader)> {
+    committed().find(|(_, header)| header.seq == seq)
+}
+
+// Frames the retained frames need to be restored, and for each of them the
+// banks (bit per bank) holding a copy. Taken from the headers alone, it is on
+// the checkpoint path; a frame that would fail its crc only keeps its bank
+// live a little longer.
+pub struct Needed {
+    chains: [(u32, u32); MAX_RETAINED_FRAMES],
+    chain_count: usize,
+    seqs: [(u32, u8); MAX_NEEDED],
+    len: usize,
+}
+
+impl Needed {
+    pub fn scan() -> Needed {
+        let mut retained = [FrameInfo::default(); MAX_RETAINED_FRAMES];
+        let count = newest_of(committed_headers(), &mut retained[..retained_count()]);
+        let mut needed = Needed { chains: [(0, 0); MAX_RETAINED_FRAMES], chain_count: count, seqs: [(0, 0); MAX_NEEDED], len: 0 };
+        for (chain, frame) in needed.chains.iter_mut().zip(retained[..count].iter()) {
+            *chain = (frame.base_seq, frame.seq);
+        }
+
+        for (address, header) in committed_headers() {
+            if !needed.needs(&header) {
+                continue;
+            }
+            let bank = 1 << bank_of(address);
+            match needed.seqs[..needed.len].iter_mut().find(|(seq, _)| *seq == header.seq) {
+                Some(entry) => entry.1 |= bank,
+                None if needed.len < MAX_NEEDED => {
+                    needed.seqs[needed.len] = (header.seq, bank);
+                    needed.len += 1;
+                }
+                None => {}
+            }
+        }
+        needed
+    }
+
+    // retained frame or part of a retained frame's delta chain
+    pub fn needs(&self, header: &FrameHeader) -> bool {
+        self.chains[..self.chain_count]
+            .iter()
+            .any(|(base_seq, seq)| header.base_seq == *base_seq && header.seq <= *seq)
+    }
+
+    // banks holding a copy of the needed frame `seq`
+    pub fn copies(&self, seq: u32) -> u8 {
+        self.seqs[..self.len].iter().find(|(s, _)| *s == seq).map_or(0, |(_, banks)| *banks)
+    }
+
+    // `bank` holds the only copy of a needed frame
+    pub fn bank_is_live(&self, bank: u32) -> bool {
+        self.seqs[..self.len].iter().any(|(_, banks)| *banks == 1 << bank)
+    }
+}
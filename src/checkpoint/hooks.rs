@@ -0,0 +1,69 @@
+#![allow(unsafe_code, non_upper_case_globals)]
+use core::ptr::{addr_of, addr_of_mut};
+
+// Peripheral set-up a resumed application expects to find again. A power
+// failure loses the clock tree, FMC timings, GPIO alternate functions, EXTI
+// lines and whatever drivers configured. Hooks run by stage, within a stage
+// in the order they were registered:
+//
+//   Clocks, Memory   first thing in restore(), restore_frame() and recover(),
+//                    before anything reads F-RAM, which sits behind the FMC
+//   Gpio, Drivers    after the frame is validated and the RAM state is back,
+//                    right before the registers are reloaded
+//
+// Hooks must not touch the stack of the code being resumed (they run on the
+// restoring stack) and must not take a checkpoint.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HookStage {
+    // RCC, PLL, flash latency
+    Clocks,
+    // FMC and anything else memory mapped through it
+    Memory,
+    // GPIO modes and alternate functions, EXTI routing
+    Gpio,
+    // user drivers, run last
+    Drivers,
+}
+
+pub const MAX_HOOKS: usize = 16;
+
+#[derive(Clone, Copy)]
+struct Hook {
+    stage: HookStage,
+    run: fn(),
+}
+
+static mut hooks: [Option<Hook>; MAX_HOOKS] = [None; MAX_HOOKS];
+
+// Adds `hook` to `stage`, registering the same function twice runs it once.
+// Returns false when the registry is full.
+pub fn register_restore_hook(stage: HookStage, hook: fn()) -> bool {
+    let table = unsafe { &mut *addr_of_mut!(hooks) };
+    if table.iter().flatten().any(|h| h.stage == stage && h.run as usize == hook as usize) {
+        return true;
+    }
+    match table.iter_mut().find(|entry| entry.is_none()) {
+        Some(entry) => {
+            *entry = Some(Hook { stage, run: hook });
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn clear_restore_hooks() {
+    unsafe { *addr_of_mut!(hooks) = [None; MAX_HOOKS] };
+}
+
+pub const EARLY_STAGES: [HookStage; 2] = [HookStage::Clocks, HookStage::Memory];
+pub const LATE_STAGES: [HookStage; 2] = [HookStage::Gpio, HookStage::Drivers];
+
+pub fn run_restore_hooks(stages: &[HookStage]) {
+    let table = unsafe { &*addr_of!(hooks) };
+    for stage in stages.iter().copied() {
+        for hook in table.iter().flatten().filter(|h| h.stage == stage) {
+            (hook.run)();
+        }
+    }
+}
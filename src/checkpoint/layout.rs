@@ -0,0 +1,95 @@
+#![allow(unsafe_code)]
+use core::ptr::addr_of;
+
+// Region bounds defined in memory.x, the link time checks for these live
+// there as well.
+extern "C" {
+    static _checkpoint_start: u32;
+    static _checkpoint_end: u32;
+    static _undo_log_start: u32;
+    static _undo_log_end: u32;
+    static _persistent_start: u32;
+    static _persistent_end: u32;
+    static _stack_shadow_start: u32;
+    static _stack_shadow_end: u32;
+    static _stack_start: u32;
+    static _stack_end: u32;
+    static _stext: u32;
+    static __etext: u32;
+    static __sdata: u32;
+    static __edata: u32;
+    static __sbss: u32;
+    static __ebss: u32;
+}
+
+// flash area holding the checkpoint banks
+pub fn checkpoint_start() -> u32 {
+    unsafe { addr_of!(_checkpoint_start) as u32 }
+}
+
+pub fn checkpoint_end() -> u32 {
+    unsafe { addr_of!(_checkpoint_end) as u32 }
+}
+
+// F-RAM area used by save_variables() / restore_globals()
+pub fn undo_log_start() -> u32 {
+    unsafe { addr_of!(_undo_log_start) as u32 }
+}
+
+pub fn undo_log_end() -> u32 {
+    unsafe { addr_of!(_undo_log_end) as u32 }
+}
+
+// F-RAM area holding .fram_section
+pub fn persistent_start() -> u32 {
+    unsafe { addr_of!(_persistent_start) as u32 }
+}
+
+pub fn persistent_end() -> u32 {
+    unsafe { addr_of!(_persistent_end) as u32 }
+}
+
+// F-RAM copy of the last checkpointed stack image, see delta.rs
+pub fn stack_shadow_start() -> u32 {
+    unsafe { addr_of!(_stack_shadow_start) as u32 }
+}
+
+pub fn stack_shadow_end() -> u32 {
+    unsafe { addr_of!(_stack_shadow_end) as u32 }
+}
+
+// main stack, grows down from _stack_start (one past its top word) towards
+// _stack_end
+pub fn stack_start() -> u32 {
+    unsafe { addr_of!(_stack_start) as u32 }
+}
+
+pub fn stack_end() -> u32 {
+    unsafe { addr_of!(_stack_end) as u32 }
+}
+
+// .text from the cortex-m-rt linker script, a saved pc has to be in here
+pub fn text_start() -> u32 {
+    unsafe { addr_of!(_stext) as u32 }
+}
+
+pub fn text_end() -> u32 {
+    unsafe { addr_of!(__etext) as u32 }
+}
+
+// RAM globals from the cortex-m-rt linker script, see globals.rs
+pub fn data_start() -> u32 {
+    unsafe { addr_of!(__sdata) as u32 }
+}
+
+pub fn data_end() -> u32 {
+    unsafe { addr_of!(__edata) as u32 }
+}
+
+pub fn bss_start() -> u32 {
+    unsafe { addr_of!(__sbss) as u32 }
+}
+
+pub fn bss_end() -> u32 {
+    unsafe { addr_of!(__ebss) as u32 }
+}
@@ -0,0 +1,131 @@
+#![allow(unsafe_code, non_upper_case_globals)]
+use core::ptr::addr_of_mut;
+
+use super::history::MAX_RETAINED_FRAMES;
+
+// Restore-loop detection. A bug shortly after a checkpoint resets the device
+// before it gets to take the next one, and every boot resumes the same frame
+// again. Every resume counts against its frame in F-RAM; committing a frame
+// is the progress marker, it clears the counts, so a count only grows while
+// no newer frame makes it to flash. restore() stops resuming a frame once its
+// count reaches restore_loop_limit and escalates instead (see
+// LoopAction).
+
+// frames restored since the last commit, the newest one and the retained
+// older ones escalation falls back to
+pub const LOOP_ENTRIES: usize = MAX_RETAINED_FRAMES;
+
+const RESTORE_COUNTS_VALID: u32 = 0x4E57_0003;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopAction {
+    // resume the newest retained frame that is still under the limit, safe
+    // mode if there is none
+    OlderFrame,
+    // resume the looping frame anyway
+    Resume,
+    // do not resume, restore() returns RestoreError::RestoreLoop and
+    // recover() reports SafeMode
+    SafeMode,
+}
+
+#[repr(C)]
+struct RestoreCounts {
+    valid: u32,
+    // seq of the newest frame committed, the progress marker
+    progress: u32,
+    // times restore() escalated since the counts were first set up
+    escalations: u32,
+    seqs: [u32; LOOP_ENTRIES],
+    // 0 marks a free entry
+    counts: [u32; LOOP_ENTRIES],
+}
+
+#[link_section = ".fram_section"]
+static mut restore_table: RestoreCounts = RestoreCounts {
+    valid: 0,
+    progress: 0,
+    escalations: 0,
+    seqs: [0; LOOP_ENTRIES],
+    counts: [0; LOOP_ENTRIES],
+};
+
+// the table, set up on first use after F-RAM was blank or held something else
+fn counts() -> &'static mut RestoreCounts {
+    unsafe {
+        let counts = &mut *addr_of_mut!(restore_table);
+        if counts.valid != RESTORE_COUNTS_VALID {
+            counts.progress = 0;
+            counts.escalations = 0;
+            counts.counts = [0; LOOP_ENTRIES];
+            counts.valid = RESTORE_COUNTS_VALID;
+        }
+        counts
+    }
+}
+
+// the frame `seq` was committed, everything restored before made progress
+pub fn frame_committed(seq: u32) {
+    let counts = counts();
+    counts.counts = [0; LOOP_ENTRIES];
+    counts.progress = seq;
+}
+
+// Counts a resume of `seq` and returns how often it has been resumed since the
+// last commit. A full table gives up the entry of the oldest frame.
+pub fn count_restore(seq: u32) -> u32 {
+    let counts = counts();
+    let index = match counts.seqs.iter().zip(counts.counts.iter()).position(|(s, c)| *c != 0 && *s == seq) {
+        Some(index) => index,
+        None => {
+            let index = match counts.counts.iter().position(|c| *c == 0) {
+                Some(index) => index,
+                None => (0..LOOP_ENTRIES).min_by_key(|i| counts.seqs[*i]).unwrap_or(0),
+            };
+            counts.counts[index] = 0;
+            counts.seqs[index] = seq;
+            index
+        }
+    };
+    counts.counts[index] = counts.counts[index].saturating_add(1);
+    counts.counts[index]
+}
+
+// times `seq` was resumed since the last commit
+pub fn restore_count(seq: u32) -> u32 {
+    let counts = counts();
+    counts
+        .seqs
+        .iter()
+        .zip(counts.counts.iter())
+        .find(|(s, c)| **c != 0 && **s == seq)
+        .map_or(0, |(_, c)| *c)
+}
+
+// Fills `out` with (seq, restores) of the frames resumed since the last
+// commit and returns how many there are.
+pub fn restore_counts(out: &mut [(u32, u32)]) -> usize {
+    let counts = counts();
+    let mut found = 0;
+    for (seq, count) in counts.seqs.iter().zip(counts.counts.iter()) {
+        if *count != 0 && found < out.len() {
+            out[found] = (*seq, *count);
+            found += 1;
+        }
+    }
+    found
+}
+
+// seq of the newest frame committed, 0 before the first one
+pub fn last_progress() -> u32 {
+    counts().progress
+}
+
+pub fn count_escalation() {
+    let counts = counts();
+    counts.escalations = counts.escalations.saturating_add(1);
+}
+
+pub fn loop_escalations() -> u32 {
+    counts().escalations
+}
@@ -1,10 +1,55 @@
 
 #![allow(unsafe_code, non_upper_case_globals)]
 pub mod my_flash;
+pub mod frame;
+pub mod crc;
+pub mod bank;
+pub mod wear;
+pub mod layout;
+pub mod delta;
+pub mod compress;
+pub mod history;
+pub mod gc;
+pub mod context;
+pub mod error;
+pub mod tasks;
+pub mod validate;
+pub mod undo;
+pub mod globals;
+pub mod hooks;
+pub mod heap;
+pub mod recovery;
+pub mod loops;
+pub mod profile;
+use frame::{FrameHeader, SectionWriter, HEADER_SIZE, STACK_END_MARKER, FLAG_JIT, FLAG_FPU, FLAG_EXCEPTION, ENCODING_RAW, ENCODING_WORD_RLE};
+use crc::{CRC_INIT, crc32_update, crc32_finish};
+use bank::{next_slot, erase_bank, retire_bank, newest_intact, BANK_COUNT, Slot};
+use layout::{undo_log_start, undo_log_end, stack_shadow_start, stack_start, stack_end};
+use delta::{delta_base, delta_body_len, write_delta, invalidate_shadow, record_shadow, copy_stack_to_shadow, set_shadow, rebuild_shadow};
+use compress::{compressed_len, write_compressed};
+use history::{find_frame, list_frames, retained_count, FrameInfo, MAX_RETAINED_FRAMES};
+pub use context::checkpoint_from_exception;
+pub use error::{CheckpointError, CheckpointStatus, RestoreError};
+use error::{decode_status, resumed_status, STATUS_OK};
+use context::{__checkpoint_capture, __checkpoint_resume, enable_fpu, Context, REG_SP, REG_MSP, REG_PSP, REG_CONTROL, REG_XPSR, XPSR_IPSR};
+use tasks::{stack_of, restore_tasks, TaskSnapshot};
+use validate::validate_frame;
+use globals::{globals_len, write_globals, restore_ram_globals};
+use heap::{restore_heap, HeapSnapshot};
+pub use heap::{register_heap, unregister_heap, LiveRanges};
+use hooks::{run_restore_hooks, EARLY_STAGES, LATE_STAGES};
+pub use hooks::{register_restore_hook, clear_restore_hooks, HookStage};
+use undo::{begin_transaction, record_logged, end_transaction, roll_back_transaction, loggable, RECORD_HEADER};
+pub use recovery::{recover, boot_report, BootDecision, BootReport, ResetCause};
+pub use tasks::{register_stack, unregister_stack};
+use loops::{count_restore, count_escalation, frame_committed};
+pub use loops::{restore_count, restore_counts, loop_escalations, last_progress, LoopAction};
+pub use profile::{op_stats, reset_profile, Operation, OpStats};
 use my_flash::{unlock, wait_ready, clear_error_flags, erase_page, write_to_flash};
 
-use core::ptr;
+use core::ptr::{self, addr_of, addr_of_mut};
 use cortex_m::asm::{nop, self};
+use cortex_m::peripheral::{scb::SystemHandler, SCB};
 use cortex_m_semihosting::hprintln;
 use panic_halt as _;
 
@@ -12,10 +57,53 @@ use::core::arch::asm;
 use stm32f3xx_hal_v2::{pac::Peripherals, pac::FLASH};
 use volatile::Volatile;
 
-pub static mut transcation_log: u32 = 0x60004000; 
+// next free byte of the undo log, start_atomic() points it at _undo_log_start
+pub static mut transcation_log: u32 = 0;
 pub static mut execution_mode: bool = true;  //1. true is jit 2.flase is static 
-
-pub fn save_variables(mem_loc: *const u8, size: usize) {
+// called by checkpoint_from_exception() once the frame is written, to clear
+// whatever raised the interrupt (e.g. the EXTI pending bit of a power-fail pin)
+pub static mut exception_ack: Option<fn()> = None;
+// RestoreError code of the last failed restore, 0 after a resume
+#[repr(C)]
+struct RestoreFailure {
+    valid: u32,
+    code: u32,
+}
+const RESTORE_FAILURE_VALID: u32 = 0x4E57_0002;
+
+#[link_section = ".fram_section"]
+static mut restore_failure: RestoreFailure = RestoreFailure { valid: 0, code: 0 };
+
+// put .data and .bss into every frame as well, see globals.rs
+pub static mut checkpoint_globals: bool = false;
+// frames kept on flash for restore_frame() and restore-loop escalation, the
+// newest one included, capped at history::MAX_RETAINED_FRAMES
+pub static mut retain_frames: usize = 4;
+// restore() escalates once the newest frame was resumed this often without a
+// newer one being committed, 0 never escalates (see loops.rs)
+pub static mut restore_loop_limit: u32 = 3;
+// picks the escalation, called with the looping frame's seq and restore
+// count; LoopAction::OlderFrame without one
+pub static mut restore_loop_hook: Option<fn(u32, u32) -> LoopAction> = None;
+
+// checkpoint requested by request_checkpoint(), taken in PendSV
+static mut checkpoint_requested: bool = false;
+static mut requested_jit: bool = false;
+// a checkpoint, erase or gc step is writing flash, nothing else may start
+// until it is done (see claim_flash())
+static mut checkpoint_busy: bool = false;
+
+// Logs the old value of `size` bytes at `mem_loc` before an atomic section
+// changes them. Returns false, logging nothing, if the record does not fit in
+// the undo log (the stack shadow follows it, start_atomic() points
+// transcation_log into it), is longer than 255 bytes or the variable is
+// neither in RAM nor a persistent variable (see undo::loggable()).
+pub fn save_variables(mem_loc: *const u8, size: usize) -> bool {
+    let log = unsafe { transcation_log };
+    let fits = log >= undo_log_start() && log.checked_add(RECORD_HEADER + size as u32).is_some_and(|end| end <= undo_log_end());
+    if size > u8::MAX as usize || !fits || !loggable(mem_loc as u32, size as u32) {
+        return false;
+    }
     unsafe{
         for i in 0..4 {
             let byte = (mem_loc as u32 >> (i * 8)) as u8; // Extract the byte at position i
@@ -33,8 +121,10 @@ pub fn save_variables(mem_loc: *const u8, size: usize) {
             ptr::write( (transcation_log+i as u32) as *mut u8 , byte);   
         }
         transcation_log =  transcation_log + size as u32;
+        record_logged(transcation_log);
     }
     hprintln!("Address: {:p}, Size: {} bytes", mem_loc, size);
+    true
 }
 
 pub fn start_atomic(){
@@ -66,178 +156,68 @@ pub fn start_atomic(){
     //         transcation_log = 0x60004000;
 
     //         }  
+    unsafe{transcation_log = undo_log_start();}
+    begin_transaction();
     unsafe{execution_mode = false;}
 }
 
 
+// A checkpoint requested from a handler during the section is taken now.
 pub fn end_atomic(){
-    unsafe {transcation_log = 0x60004000;}
+    end_transaction();
+    unsafe {transcation_log = undo_log_start();}
     unsafe {execution_mode = true;}
+    if unsafe { checkpoint_requested } {
+        SCB::set_pendsv();
+    }
+}
 
+// Registers are captured by __checkpoint_capture (see context.rs) before any
+// prologue runs, the frame itself is written by save_frame(). Like setjmp it
+// returns twice: Checkpointed once the frame is written, and Resumed when
+// restore() brings the frame back, so the caller can re-arm peripherals or
+// skip work that must not run twice.
+#[inline(always)]
+pub fn checkpoint(c_type:bool) -> Result<CheckpointStatus, CheckpointError>{
+    decode_status(unsafe { __checkpoint_capture(c_type) })
 }
 
 #[no_mangle]
-pub fn checkpoint(c_type:bool){
-
-    unsafe {
-        asm!(
-            "add sp, #280"
-        );
-    }
-    unsafe {
-        asm!(
-            "pop {{r7}}"
-        );
-    }
-    unsafe {
-        asm!(
-            "push {{r7}}"
-        );
+extern "C" fn save_frame(context: &Context, c_type: bool) -> u32{
+    match write_frame(context, if c_type { FLAG_JIT } else { 0 }) {
+        Ok(()) => STATUS_OK,
+        Err(error) => error.status(),
     }
-    unsafe {
-        asm!(
-            "sub sp, #280"
-        );
-    }
-
-    let r0_value: u32;
-    let r1_value: u32;
-    let r2_value: u32;
-    let r3_value: u32;
-    let r4_value: u32;
-    let r5_value: u32;
-    let r6_value: u32;
-    let r7_value: u32;
-    let r8_value: u32;
-    let r9_value: u32;
-    let r10_value: u32;
-    let r11_value: u32;
-    let r12_value: u32;
-    let r13_sp: u32;
-    let r14_lr: u32;
-    let r15_pc: u32;
-
-    unsafe {
-        asm!(
-            "MOV {0}, r0",
-            out(reg) r0_value
-        );
-    }
-
-    unsafe {
-        asm!(
-            "MOV {0}, r1",
-            out(reg) r1_value
-        );
-    }
-
-    unsafe {
-        asm!(
-            "MOV {0}, r2",
-            out(reg) r2_value
-        );
-    }
-    unsafe {
-        asm!(
-            "MOV {0}, r3",
-            out(reg) r3_value
-        );
-    }
-
-    unsafe {
-        asm!(
-            "MOV {0}, r4",
-            out(reg) r4_value
-        );
-    }
-
-    unsafe {
-        asm!(
-            "MOV {0}, r5",
-            out(reg) r5_value
-        );
-    }
-
-    unsafe {
-        asm!(
-            "MOV {0}, r6",
-            out(reg) r6_value
-        );
-    }
-
-    unsafe {
-        asm!(
-            "MOV {0}, r7",
-            out(reg) r7_value
-        );
-    }
-
-    unsafe {
-        asm!(
-            "MOV {0}, r8",
-            out(reg) r8_value
-        );
-    }
-
-    unsafe {
-        asm!(
-            "MOV {0}, r9",
-            out(reg) r9_value
-        );
-    }
-
-    unsafe {
-        asm!(
-            "MOV {0}, r10",
-            out(reg) r10_value
-        );
-    }
-    unsafe {
-        asm!(
-            "MOV {0}, r11",
-            out(reg) r11_value
-        );
-    }
-
+}
 
-    unsafe {
-        asm!(
-            "MOV {0}, r12",
-            out(reg) r12_value
-        );
+fn write_frame(context: &Context, mut flags: u16) -> Result<(), CheckpointError>{
+    let measurement = profile::begin();
+    // checkpoint() from a handler, the frame would resume the handler's
+    // context in Thread mode (exception frames carry the thread's xPSR)
+    if context.registers[REG_XPSR] & XPSR_IPSR != 0 {
+        return Err(CheckpointError::HandlerMode);
     }
- 
-    unsafe {
-        asm!(
-            "MOV {0}, r14",
-            out(reg) r14_lr
-        );
-    }
-    unsafe {
-        asm!(
-            "MOV {0}, r15",
-            out(reg) r15_pc
-        );
+    // the stack image is everything between the main stack pointer and
+    // _stack_start, an sp outside _stack_end.._stack_start is never
+    // persisted. Code running on PSP needs its stack registered (tasks.rs).
+    let on_psp = context.registers[REG_CONTROL] & 2 != 0;
+    let active_psp = if on_psp { Some(context.registers[REG_PSP]) } else { None };
+    if let Some(psp) = active_psp {
+        match stack_of(psp) {
+            Some(stack) if psp == stack.bottom => return Err(CheckpointError::StackOverflow),
+            Some(_) => {}
+            None => return Err(CheckpointError::StackOutOfRange),
+        }
     }
-
-  //  r14_lr = lr;
-
-    unsafe {
-        asm!(
-            "MOV r0, sp",
-        );
+    let r13_sp = if on_psp { context.registers[REG_MSP] } else { context.registers[REG_SP] };
+    if r13_sp < stack_end() {
+        return Err(CheckpointError::StackOverflow);
     }
-    // have to be extra careful for the sp value
-    unsafe {
-        asm!(
-            "add r0, #288",
-        );
+    if r13_sp >= stack_start() || !r13_sp.is_multiple_of(4) {
+        return Err(CheckpointError::StackOutOfRange);
     }
-    unsafe {
-        asm!(
-            "MOV {0}, r0",
-            out(reg) r13_sp
-        );
+    if !claim_flash() {
+        return Err(CheckpointError::Busy);
     }
     unsafe{
 
@@ -247,48 +227,60 @@ pub fn checkpoint(c_type:bool){
         wait_ready(&flash);
 
    
-        //let  start_address: u32 = 0x2000_fffc as u32;
-        let mut start_address:u32;
+        // word 0 of the image is the topmost stack word
+        let mut start_address:u32 = stack_start() - 4;
         let  end_address = r13_sp;
-        asm!("movw r0, 0xFFF8
-             movt r0, 0x2000");
-
-         asm!(
-             "MOV {0}, r0",
-             out(reg) start_address
-         );
-
-         let stack_size = (start_address - end_address) + 4;
-        // leaving first xyz K for program i.e start at 0x0801_0000
-         let mut flash_start_address = Volatile::new(0x0803_0000);
-         let mut flash_end_address = Volatile::new(0x0807_FFFF);    
 
+         let stack_size = stack_start() - end_address;
+        let mut flash_start_address = Volatile::new(0u32);
         let mut checkpoint_size= Volatile::new(0u32);
+        let mut crc = Volatile::new(CRC_INIT);
         asm::dmb();
-        // 1. stack size
-        // 2. 4 bytes -> 0xf1f1_f1f1 (end of stack in the frame magic number)
-        // 3. 16 * 4 -> all the cpu registers
-        // 4. 4 bytes -> size of frame
-        // 5. 4 bytes -> 0xDEADBEEF (magic number to indicate the static checkpoint)
-        checkpoint_size.write(stack_size+4+16*4 +4 +4);
+        // 1. frame header (see frame.rs)
+        // 2. stack size
+        // 3. 4 bytes -> 0xf1f1_f1f1 (end of stack in the frame magic number)
+        // 4. 23 * 4 -> all the cpu and special registers (+ 33 * 4 fpu registers with FLAG_FPU)
+        // 5. .data and .bss with checkpoint_globals (see globals.rs)
+        // 6. the heap, if one is registered (see heap.rs)
+        // 7. task stacks, if any are registered (see tasks.rs)
+        // 8. 4 bytes -> crc over 2 - 7
+        if context.fpu_saved != 0 {
+            flags |= FLAG_FPU;
+        }
+        let tasks = TaskSnapshot::take(active_psp);
+        let task_len = tasks.section_len();
+        let heap = HeapSnapshot::take();
+        let heap_len = heap.section_len();
+        let globals = if checkpoint_globals { globals_len() } else { 0 };
+        checkpoint_size.write(FrameHeader::new(0, flags, stack_size).total_len + globals + heap_len + task_len);
         asm::dmb();
 
-        loop{
-            let mut offset = ptr::read_volatile(flash_start_address.read() as *const u32);
-            if offset == 0xffff_ffff{
-                break;
-            }
-            flash_start_address.write(flash_start_address.read() + offset); 
-            if flash_start_address.read() + checkpoint_size.read() >= flash_end_address.read() {
-               erase_all(&mut flash);
-               flash_start_address = Volatile::new(0x0803_0000);
-               break;
+        // a full bank is not erased here, the frame goes to the next bank and
+        // the full one is retired after the commit
+        let slot = match next_slot(&mut flash, checkpoint_size.read()) {
+            Some(slot) => slot,
+            None => {
+                release_flash();
+                return Err(CheckpointError::FrameTooLarge);
             }
-        }
+        };
+        flash_start_address.write(slot.address);
         asm::dmb();
-        //write the size of packet at the begining of the packet
-        write_to_flash(&mut flash,  (flash_start_address.read()) as u32, checkpoint_size.read() as u32); 
-        flash_start_address.write(flash_start_address.read()+4);
+        //write the header at the begining of the packet
+        let words = stack_size / 4;
+        let mut header = plan_frame(&slot, flags, start_address, stack_size);
+        if globals > 0 {
+            header = header.with_globals(globals);
+        }
+        if heap_len > 0 {
+            header = header.with_heap(heap_len);
+        }
+        if task_len > 0 {
+            header = header.with_tasks(task_len);
+        }
+        let frame_address = flash_start_address.read();
+        header.write(&mut flash, frame_address);
+        flash_start_address.write(flash_start_address.read() + HEADER_SIZE);
         asm::dmb();
            // Code that involves Flash write
     //      if offset == 0xffff_ffff {
@@ -309,26 +301,38 @@ pub fn checkpoint(c_type:bool){
     //         write_to_flash(&mut flash,  0x0801_0000 as u32, offset+checkpoint_size+1-1  as u32);
     //      }
     asm::dmb(); 
-        if c_type {
-            //write at the begining of checkpoint fram so magic number indicate jit or static checkpoint
-            write_to_flash(&mut flash,  flash_start_address.read() as u32, 0xDEADBEEF as u32);
+        if header.is_delta() {
+            let (address, frame_crc) = write_delta(&mut flash, flash_start_address.read(), start_address, words, crc.read());
+            flash_start_address.write(address);
+            crc.write(frame_crc);
         }
-        else{
-            write_to_flash(&mut flash,  flash_start_address.read() as u32,  0x0000_0001 as u32);
+        else if header.encoding() == ENCODING_WORD_RLE {
+            invalidate_shadow();
+            let (address, frame_crc) = write_compressed(&mut flash, flash_start_address.read(), start_address, words, crc.read());
+            flash_start_address.write(address);
+            crc.write(frame_crc);
+            copy_stack_to_shadow(start_address, words);
         }
+        else {
+         invalidate_shadow();
+         let stack_top = start_address;
          while start_address >= end_address{
             let mut data = Volatile::new(0u32);
             data.write(core::ptr::read_volatile(start_address as * const u32));
             write_to_flash(&mut flash,  flash_start_address.read() as u32, data.read() as u32);
+            crc.write(crc32_update(crc.read(), data.read()));
+            record_shadow((stack_top - start_address) / 4, data.read());
             flash_start_address.write(flash_start_address.read() +1* 4);
             // Move to the next address based on the size of the type
             start_address = start_address-4;
             
+        }
         }
         asm::dmb();
     asm::dmb();
     //mark the end of the stack
-    write_to_flash(&mut flash,  (flash_start_address.read()) as u32, 0xf1f1_f1f1 as u32);
+    write_to_flash(&mut flash,  (flash_start_address.read()) as u32, STACK_END_MARKER);
+    crc.write(crc32_update(crc.read(), STACK_END_MARKER));
     flash_start_address.write(flash_start_address.read() + 4);
     asm::dmb();
 
@@ -337,194 +341,340 @@ pub fn checkpoint(c_type:bool){
     //       flash_start_address = flash_start_address + 4;
     // }
 
-    write_to_flash(&mut flash,  flash_start_address.read() as u32, r0_value as u32);
-    write_to_flash(&mut flash,  flash_start_address.read()+4 as u32, r1_value as u32);
-    write_to_flash(&mut flash,  flash_start_address.read()+8 as u32, r2_value as u32);
-    write_to_flash(&mut flash,  flash_start_address.read()+12 as u32, r3_value as u32);
-    write_to_flash(&mut flash,  flash_start_address.read()+16 as u32, r4_value as u32);
-    write_to_flash(&mut flash,  flash_start_address.read()+20 as u32, r5_value as u32);
-    write_to_flash(&mut flash,  flash_start_address.read()+24 as u32, r6_value as u32);
-    write_to_flash(&mut flash,  flash_start_address.read()+28 as u32, r7_value as u32);
-    write_to_flash(&mut flash,  flash_start_address.read()+32 as u32, r8_value as u32);
-    write_to_flash(&mut flash,  flash_start_address.read()+36 as u32, r9_value as u32);
-    write_to_flash(&mut flash,  flash_start_address.read()+40 as u32, r10_value as u32);
-    write_to_flash(&mut flash,  flash_start_address.read()+44 as u32, r11_value as u32);
-    write_to_flash(&mut flash,  flash_start_address.read()+48 as u32, r12_value as u32);
-    write_to_flash(&mut flash,  flash_start_address.read()+52 as u32, r13_sp as u32);
-    write_to_flash(&mut flash,  flash_start_address.read()+56 as u32, r14_lr as u32);
-    write_to_flash(&mut flash,  flash_start_address.read()+60 as u32, r15_pc as u32);
+    for register in context.registers.iter() {
+        write_to_flash(&mut flash,  flash_start_address.read(), *register);
+        crc.write(crc32_update(crc.read(), *register));
+        flash_start_address.write(flash_start_address.read() + 4);
+    }
+    if header.has_fpu() {
+        for register in context.fpu.iter() {
+            write_to_flash(&mut flash,  flash_start_address.read(), *register);
+            crc.write(crc32_update(crc.read(), *register));
+            flash_start_address.write(flash_start_address.read() + 4);
+        }
+    }
+    // globals, heap and task sections, each only if the header has it
+    let mut sections = SectionWriter::new(&mut flash, flash_start_address.read(), crc.read());
+    if header.has_globals() {
+        write_globals(&mut sections);
+    }
+    heap.write(&mut sections);
+    tasks.write(&mut sections);
+    let (address, frame_crc) = sections.finish();
+    flash_start_address.write(address);
+    crc.write(frame_crc);
+    asm::dmb();
+    // the crc goes last, a frame torn before this point never verifies
+    write_to_flash(&mut flash,  flash_start_address.read(), crc32_finish(crc.read()));
+    asm::dmb();
+    // only now the frame becomes visible to restore()
+    FrameHeader::commit(&mut flash, frame_address);
+    frame_committed(header.seq);
+    set_shadow(&header);
+    if let Some((bank, needed)) = &slot.retire {
+        retire_bank(&mut flash, *bank, needed);
+    }
     drop(flash);
     }     
+    release_flash();
+    profile::end(Operation::Checkpoint, measurement);
+    Ok(())
 }
 
-pub fn erase_all(flash: &mut FLASH){
-    let start_address = 0x0803_0000;
-
-    for i in 0..100{
-        let page = start_address + i * 2*1024;
-         erase_page(flash,  page);
-    }
-
+// Takes the flash for a checkpoint, erase or gc step, false if one is running
+// already: a handler that interrupted it must not start writing, a nested
+// write_to_flash() relocks the flash and leaves the outer frame torn.
+fn claim_flash() -> bool{
+    cortex_m::interrupt::free(|_| unsafe {
+        if checkpoint_busy {
+            false
+        } else {
+            checkpoint_busy = true;
+            true
+        }
+    })
 }
 
-pub fn restore_globals(){
-    unsafe{
-        loop {
-            let mut combined:u32 = 0;
-            for i in 0..4 {
-                combined |= (ptr::read((transcation_log + i) as *const u32) << (i * 8));
-            
-            }
-            
-            let mut size:u8 = ptr::read( transcation_log as *const u8);
-        
-            for i in 0..size{
-                ptr::write((combined + i as u32) as *mut u8,*((transcation_log + i as u32) as *const u8));
-            }
-            combined =  combined + size as u32;
-
-            let end = ptr::read(combined as *const u8);
-            
-            if end == 0xFB{
-                break;
-            }
+// ends claim_flash(), a checkpoint requested in the meantime is taken now
+fn release_flash(){
+    unsafe {
+        checkpoint_busy = false;
+        if checkpoint_requested {
+            SCB::set_pendsv();
         }
     }
 }
-pub fn restore()->bool{
+
+// Ask for a checkpoint from any handler or thread code. It is taken in PendSV,
+// at the lowest priority, once every other handler has returned and no
+// checkpoint is being written; a jit request wins over a static one pending
+// at the same time.
+pub fn request_checkpoint(c_type: bool){
     unsafe {
-        let mut flash_start_address = 0x0803_0000;
-        let packet_size = ptr::read_volatile(0x0803_0000 as *const u32);
-        //let r0_flash = ptr::read_volatile(0x0800_9060 as *const u32);
-        if packet_size == 0xffff_ffff {
-            return false
-        }
-        if  ptr::read_volatile((flash_start_address + packet_size) as *const u32)==0xffff_ffff{
-            return  false;
-        }
+        let mut cp = cortex_m::Peripherals::steal();
+        cp.SCB.set_priority(SystemHandler::PendSV, 0xff);
+        cortex_m::interrupt::free(|_| {
+            requested_jit |= c_type;
+            checkpoint_requested = true;
+        });
+    }
+    SCB::set_pendsv();
+}
 
-        let mut offset:u32 = 0;
-        // think about multiple conditions where it could break
-        //1. There could multiple failed checkpoints before a successful checkpoint.
-        //2. The last checkpoint could be a failed(incomplete) checkpoint.
-        loop{
-            
-            offset = ptr::read_volatile(flash_start_address  as *const u32);
-  
-            if  ptr::read_volatile((flash_start_address + offset) as *const u32) == 0xffff_ffff{
-                break;
-            }
-    
-            flash_start_address+=offset;
+// PendSV half of request_checkpoint(), a request that finds a checkpoint in
+// progress is left pending and re-raised when that one is done, one that
+// finds an atomic section is taken by end_atomic().
+#[no_mangle]
+extern "C" fn save_pendsv_frame(context: &Context){
+    unsafe {
+        if checkpoint_busy || !checkpoint_requested || !execution_mode {
+            return;
         }
-        flash_start_address+=4;
-
-        if  ptr::read_volatile(flash_start_address as *const u32) == 0xDEAD_BEEF{
-            restore_globals();
+        let c_type = requested_jit;
+        checkpoint_requested = false;
+        requested_jit = false;
+        let flags = if c_type { FLAG_JIT } else { 0 };
+        // put back for release_flash(), pending PendSV from PendSV would
+        // only re-enter it before the flash is released
+        if let Err(CheckpointError::Busy) = write_frame(context, flags | FLAG_EXCEPTION) {
+            checkpoint_requested = true;
+            requested_jit |= c_type;
         }
+    }
+}
 
-        // let mut end_address = 0x0801_0004 + packet_size;
-        // let recent_frame_size: u32 = ptr::read_volatile(end_address as *const u32);
-        // let mut recent_frame_start_address = end_address - recent_frame_size;
-
-        flash_start_address+=4;
-
-        asm!(
-            "mov r0, {0}",
-            in(reg) flash_start_address
-        );
-
-        //set sp to 0x0200_fffc
-        asm!("movw r1, 0xfff8
-        movt r1, 0x02000");
-        asm!("msr msp, r1");
-
-        asm!("movw r3, 0xf1f1
-        movt r3, 0xf1f1");
-    
-        asm!("1:
-            ldr r1, [r0, #4]
-            cmp r1, r3
-            beq 2f
-            push {{r1}}
-            adds r0, r0, #4
-            b 1b
-            2:");     
-
-        asm!("adds r0, r0, #4");
-        asm!("adds r0, r0, #4");
-
-        asm!( "LDR r1, [r0]");
-        asm!("Push {{r1}}");
-
-        asm!("adds r0, r0, #4");
-        asm!( "LDR r1, [r0]");
-
-        asm!("adds r0, r0, #4");
-        asm!( "LDR r2, [r0]");
-
-        asm!("adds r0, r0, #4");
-        asm!( "LDR r3, [r0]");
-
-        asm!("adds r0, r0, #4");
-        asm!( "LDR r4, [r0]");
-
-        asm!("adds r0, r0, #4");
-        asm!( "LDR r5, [r0]");
-
-        asm!("adds r0, r0, #4");
-        asm!( "LDR r6, [r0]");
-
-        asm!("adds r0, r0, #4");
-        asm!( "LDR r7, [r0]");
-
-        asm!("adds r0, r0, #4");
-        asm!( "LDR r8, [r0]");
-
-        asm!("adds r0, r0, #4");
-        asm!( "LDR r9, [r0]");
-
-        asm!("adds r0, r0, #4");
-        asm!( "LDR r10, [r0]");
+// Called by checkpoint_from_exception() with the interrupted thread's context.
+// An interrupted checkpoint, erase or gc step is finished first, the frame is
+// taken in PendSV right after it. So is an interrupted atomic section: rolling
+// its undo log back on restore would also undo the writes made before the
+// interrupt, which the resumed thread does not make again, so the frame waits
+// for end_atomic().
+#[no_mangle]
+extern "C" fn save_exception_frame(context: &Context){
+    if !unsafe { execution_mode } || matches!(write_frame(context, FLAG_EXCEPTION), Err(CheckpointError::Busy)) {
+        request_checkpoint(false);
+    }
+    if let Some(ack) = unsafe { exception_ack } {
+        ack();
+    }
+}
 
-        asm!("adds r0, r0, #4");
-        asm!( "LDR r11, [r0]");
+// checkpoint_from_exception() or PendSV preempted another handler, or the
+// thread is inside an IT block or LDM/STM. The power-fail checkpoint is handed
+// to PendSV, which runs once every handler has returned; PendSV itself leaves
+// the request pending for the next request_checkpoint() or release_flash().
+#[no_mangle]
+extern "C" fn checkpoint_deferred(from_pendsv: bool){
+    if from_pendsv {
+        return;
+    }
+    request_checkpoint(false);
+    if let Some(ack) = unsafe { exception_ack } {
+        ack();
+    }
+}
 
-        asm!("adds r0, r0, #4");
-        asm!( "LDR r12, [r0]");
+// Picks how the stack image below `top` is stored: only what changed since the
+// last frame when the delta chain allows it, otherwise the full image, run
+// length encoded with the `compress` feature. Whatever is smaller than the raw
+// image wins.
+fn plan_frame(slot: &Slot, flags: u16, top: u32, stack_size: u32) -> FrameHeader {
+    let words = stack_size / 4;
+    if let Some(base_seq) = delta_base(slot.last, slot.address, slot.seq, words) {
+        let body_len = delta_body_len(top, words);
+        if body_len < stack_size {
+            return FrameHeader::delta(slot.seq, base_seq, flags, stack_size, body_len);
+        }
+    }
+    if cfg!(feature = "compress") {
+        let body_len = compressed_len(top, words);
+        if body_len < stack_size {
+            return FrameHeader::encoded(slot.seq, flags, ENCODING_WORD_RLE, stack_size, body_len);
+        }
+    }
+    FrameHeader::new(slot.seq, flags, stack_size)
+}
 
-        asm!("adds r0, r0, #4");
-        //asm!( "LDR r13, [r0]"); //no need to do this
+// false if a checkpoint or gc step is writing flash, nothing is erased then
+pub fn erase_all(flash: &mut FLASH) -> bool{
+    if !claim_flash() {
+        return false;
+    }
+    let measurement = profile::begin();
+    for bank in 0..BANK_COUNT{
+        erase_bank(flash, bank);
+    }
+    profile::end(Operation::EraseAll, measurement);
+    release_flash();
+    true
+}
 
-        asm!("adds r0, r0, #4");
-        asm!( "LDR r14, [r0]");
+// undoes the transaction a jit frame was taken in, see undo.rs
+pub fn restore_globals(){
+    let measurement = profile::begin();
+    unsafe { transcation_log = undo_log_start(); }
+    roll_back_transaction();
+    profile::end(Operation::RestoreGlobals, measurement);
+}
+// Only returns if there is nothing to resume, the error says why (and is kept
+// for last_restore_error()) and the caller cold boots instead.
+pub fn restore()->Result<(), RestoreError>{
+    run_restore_hooks(&EARLY_STAGES);
+    restore_newest()
+}
 
-        asm!("POP {{r0}}");
-        
-        asm!("adds sp, sp, #56");
-        asm!("adds sp, sp, #8");
+// restore() without the early hooks, recover() has run them already
+fn restore_newest()->Result<(), RestoreError>{
+    // newest committed frame over all banks, frames from another firmware
+    // version are skipped, not misread, and so are uncommitted frames and
+    // frames whose crc does not match (torn by a power failure)
+    let result = match newest_intact() {
+        Some((_, header)) if in_restore_loop(header.seq) => escalate(header.seq),
+        Some((frame_address, header)) => resume(frame_address, &header),
+        None => Err(RestoreError::NoFrame),
+    };
+    record_restore_error(result)
+}
 
-        // asm!("POP {{r0, r1, r2, r3, r12, lr}}");
-        // asm!("LDMIA sp!, {{pc, xPSR}}");
+fn in_restore_loop(seq: u32) -> bool{
+    let limit = unsafe { restore_loop_limit };
+    limit != 0 && restore_count(seq) >= limit
+}
 
-        asm!("POP {{r0, r1, r2, r3}}");
-        asm!("adds sp, sp, #4");
-        asm!("POP {{r4}}");
-        asm!("adds sp, sp, #16"); //stack alignment issue
-        asm!("adds sp, sp, #64"); //stack alignment issue
+// The newest frame `seq` keeps getting resumed, ask restore_loop_hook what to
+// do. Older frames are tried newest first, skipping those that loop as well
+// and those that fail validation.
+fn escalate(seq: u32)->Result<(), RestoreError>{
+    count_escalation();
+    let action = match unsafe { restore_loop_hook } {
+        Some(hook) => hook(seq, restore_count(seq)),
+        None => LoopAction::OlderFrame,
+    };
+    match action {
+        LoopAction::Resume => match find_frame(seq) {
+            Some((frame_address, header)) => resume(frame_address, &header),
+            None => Err(RestoreError::NoFrame),
+        },
+        LoopAction::OlderFrame => {
+            let mut frames = [FrameInfo::default(); MAX_RETAINED_FRAMES];
+            let found = list_frames(&mut frames[..retained_count()]);
+            for frame in frames[..found].iter().filter(|f| f.seq < seq && !in_restore_loop(f.seq)) {
+                if let Some((frame_address, header)) = find_frame(frame.seq) {
+                    // only returns if the frame cannot be resumed
+                    let _ = resume(frame_address, &header);
+                }
+            }
+            Err(RestoreError::RestoreLoop)
+        }
+        LoopAction::SafeMode => Err(RestoreError::RestoreLoop),
+    }
+}
 
+// Restore an older retained frame instead of the newest one (see
+// history::list_frames), e.g. when the newest one leads to a crash loop.
+pub fn restore_frame(seq: u32)->Result<(), RestoreError>{
+    run_restore_hooks(&EARLY_STAGES);
+    let result = match find_frame(seq) {
+        Some((frame_address, header)) => resume(frame_address, &header),
+        None => Err(RestoreError::NoFrame),
+    };
+    record_restore_error(result)
+}
 
-       // asm!("POP {{r4, r5, r6, r7}}");
-       // asm!("MSR xPSR, r7");
-        asm!("mov pc, r4");    // pc is r15
-        //asm!("mov r15, r14"); // I am writing my own function to handle interrupt 
+// reason the last restore() or restore_frame() returned, None if the last
+// one resumed
+pub fn last_restore_error() -> Option<RestoreError>{
+    let failure = unsafe { &*addr_of!(restore_failure) };
+    if failure.valid != RESTORE_FAILURE_VALID {
+        return None;
+    }
+    RestoreError::from_code(failure.code)
+}
 
+fn record_restore_error(result: Result<(), RestoreError>) -> Result<(), RestoreError>{
+    let code = match result {
+        Ok(()) => 0,
+        Err(error) => error.code(),
+    };
+    unsafe {
+        let failure = &mut *addr_of_mut!(restore_failure);
+        failure.code = code;
+        failure.valid = RESTORE_FAILURE_VALID;
+    }
+    result
+}
 
+// Only returns if the frame fails validation or its stack image cannot be
+// rebuilt, nothing outside the F-RAM shadow has been changed by then.
+fn resume(frame_address: u32, header: &FrameHeader)->Result<(), RestoreError>{
+    let measurement = profile::begin();
+    validate_frame(frame_address, header)?;
+    unsafe {
+        // the stack image is rebuilt in the F-RAM shadow (delta frames need it,
+        // and the next checkpoint can be a delta of this one), a full frame can
+        // still be pushed straight from flash if that fails
+        let stack_image = if rebuild_shadow(frame_address, header) {
+            stack_shadow_start()
+        } else if !header.is_delta() && header.encoding() == ENCODING_RAW {
+            frame_address + HEADER_SIZE
+        } else {
+            return Err(RestoreError::BrokenChain);
+        };
+        let stack_words = header.stack_len / 4;
+        let registers = frame_address + header.reg_offset;
+
+        // task stacks go back before the switch to the saved context
+        let tasks_offset = header.tasks_offset(frame_address);
+        if header.has_tasks() && !restore_tasks(frame_address + tasks_offset, header.crc_offset() - tasks_offset) {
+            return Err(RestoreError::TaskStacks);
+        }
+        // the heap, checked by validate_frame()
+        if let Some(offset) = header.heap_offset(frame_address) {
+            restore_heap(frame_address + offset);
+        }
+        // RAM globals go back last, the task registry above is one of them;
+        // the frame was written with a checkpoint in progress, which is over
+        if let Some(offset) = header.globals_offset() {
+            restore_ram_globals(frame_address + offset);
+            checkpoint_busy = false;
+            checkpoint_requested = false;
+            requested_jit = false;
+        }
+        // GPIO and drivers back to what the resumed code configured, clocks
+        // and the FMC were set up before F-RAM was touched
+        run_restore_hooks(&LATE_STAGES);
+        if header.is_jit() {
+            restore_globals();
+        }
+        record_restore_error(Ok(()));
+
+        //set sp to _stack_start, push the stack image back and jump into the
+        //register block, pc is the return address of checkpoint()
+        let fpu = match header.fpu_registers(frame_address) {
+            Some(fpu) => {
+                enable_fpu();
+                fpu
+            }
+            None => 0,
+        };
+        // checkpoint() returns Resumed, a frame from an exception gets the
+        // interrupted thread's r0 back
+        let restores = count_restore(header.seq);
+        let r0 = if header.is_exception() {
+            header.register(frame_address, 0)
+        } else {
+            resumed_status(restores)
+        };
+        profile::end(Operation::Restore, measurement);
+        __checkpoint_resume(stack_image, stack_words, registers, stack_start(), fpu, r0);
     }
-    return true;
 }
 
-pub fn delete_pg(page: u32){
+// false if a checkpoint or gc step is writing flash, see erase_all()
+pub fn delete_pg(page: u32) -> bool{
+    if !claim_flash() {
+        return false;
+    }
     unsafe{
     let mut dp = Peripherals::steal();
     let mut flash= &mut dp.FLASH;
@@ -532,19 +682,12 @@ pub fn delete_pg(page: u32){
     wait_ready(&flash);
     erase_page(&mut flash,  page);
     }
+    release_flash();
+    true
 }
-pub fn delete_all_pg(){
-    let start_address = 0x0803_0000;
+pub fn delete_all_pg() -> bool{
     unsafe{
         let mut dp = Peripherals::steal();
-        let mut flash= &mut dp.FLASH;
-        for i in 0..25{
-            let page = start_address + i * 2*1024;
-            unlock(&mut flash); 
-            wait_ready(&flash);
-            erase_page(&mut flash,  page);
-        }
-       // drop(flash);
+        erase_all(&mut dp.FLASH)
     }
-
-}
\ No newline at end of file
+}
@@ -17,6 +17,21 @@ use stm32f3xx_hal_v2::hal::blocking::rng::Read;
 const UNLOCK_KEY1: u32 = 0x4567_0123;
 const UNLOCK_KEY2: u32 = 0xCDEF_89AB;
 
+// Flash traffic for profile.rs, which only looks at differences. Kept in
+// F-RAM, never initialised, so restoring .data/.bss does not move them.
+#[link_section = ".fram_section"]
+static mut words_written: u32 = 0;
+#[link_section = ".fram_section"]
+static mut pages_erased: u32 = 0;
+
+pub fn flash_words_written() -> u32 {
+    unsafe { words_written }
+}
+
+pub fn flash_pages_erased() -> u32 {
+    unsafe { pages_erased }
+}
+
 pub fn unlock(flash: &mut FLASH) ->bool{
 
     if flash.cr.read().lock().bit_is_clear(){
@@ -78,6 +93,9 @@ pub fn erase_page(flash: &mut FLASH, page: u32){
     // 6. lock the flash
     while flash.sr.read().bsy().bit_is_set() {}
     flash.cr.modify(|_, w| w.lock().set_bit());
+    if cfg!(feature = "profile") {
+        unsafe { pages_erased = pages_erased.wrapping_add(1) };
+    }
 
 }
 
@@ -116,5 +134,8 @@ pub fn write_to_flash(flash: &mut FLASH, addr: u32, data: u32) {
          // 6. Clear the PG bit in the FLASH_CR register if there no more programming request
         // anymore.
         flash.cr.modify(|_, w| w.pg().clear_bit());
+        if cfg!(feature = "profile") {
+            unsafe { words_written = words_written.wrapping_add(1) };
+        }
 
 }
\ No newline at end of file
@@ -0,0 +1,143 @@
+#![allow(unsafe_code, non_upper_case_globals)]
+use core::ptr::{addr_of, addr_of_mut};
+use cortex_m::peripheral::DWT;
+
+use super::my_flash::{flash_words_written, flash_pages_erased};
+
+// Cost of the checkpoint operations, for sizing the energy buffer. With the
+// `profile` feature every operation is timed with the DWT cycle counter and
+// the flash words it wrote and pages it erased are counted (my_flash.rs);
+// the totals go to a statistics block in F-RAM, so they add up over power
+// cycles until reset_profile(). Without the feature nothing is recorded.
+//
+// What is measured:
+//   Checkpoint       writing the frame, from save_frame() to the commit and
+//                    retiring the old bank, taken from checkpoint(),
+//                    request_checkpoint() and checkpoint_from_exception()
+//                    alike; capturing the registers is not included
+//   Restore          a successful restore(), restore_frame() or boot
+//                    recovery, up to the jump into the frame; pushing the
+//                    stack image back and reloading the registers is not
+//   EraseAll         erase_all()
+//   RestoreGlobals   restore_globals()
+//
+// The cycle counter is 32 bits, an operation must take less than 2^32 cycles
+// (about a minute at 72 MHz) to be measured right.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Checkpoint,
+    Restore,
+    EraseAll,
+    RestoreGlobals,
+}
+
+const OPERATIONS: usize = 4;
+
+impl Operation {
+    fn index(self) -> usize {
+        match self {
+            Operation::Checkpoint => 0,
+            Operation::Restore => 1,
+            Operation::EraseAll => 2,
+            Operation::RestoreGlobals => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OpStats {
+    pub count: u32,
+    pub total_cycles: u64,
+    pub min_cycles: u32,
+    pub max_cycles: u32,
+    pub bytes_written: u64,
+    pub pages_erased: u32,
+}
+
+impl OpStats {
+    pub fn average_cycles(&self) -> u32 {
+        if self.count == 0 {
+            return 0;
+        }
+        (self.total_cycles / self.count as u64) as u32
+    }
+
+    pub fn average_bytes(&self) -> u32 {
+        if self.count == 0 {
+            return 0;
+        }
+        (self.bytes_written / self.count as u64) as u32
+    }
+}
+
+const PROFILE_VALID: u32 = 0x9F0F_0001;
+
+#[repr(C)]
+struct ProfileBlock {
+    valid: u32,
+    stats: [OpStats; OPERATIONS],
+}
+
+#[link_section = ".fram_section"]
+static mut profile_block: ProfileBlock = ProfileBlock {
+    valid: 0,
+    stats: [OpStats { count: 0, total_cycles: 0, min_cycles: 0, max_cycles: 0, bytes_written: 0, pages_erased: 0 }; OPERATIONS],
+};
+
+// Where an operation started, from begin().
+#[derive(Clone, Copy)]
+pub struct Measurement {
+    cycles: u32,
+    words: u32,
+    pages: u32,
+}
+
+// Starts timing an operation, turns the cycle counter on if it is not yet.
+pub fn begin() -> Measurement {
+    if !cfg!(feature = "profile") {
+        return Measurement { cycles: 0, words: 0, pages: 0 };
+    }
+    if !DWT::cycle_counter_enabled() {
+        let mut cp = unsafe { cortex_m::Peripherals::steal() };
+        cp.DCB.enable_trace();
+        cp.DWT.enable_cycle_counter();
+    }
+    Measurement { cycles: DWT::cycle_count(), words: flash_words_written(), pages: flash_pages_erased() }
+}
+
+// Adds the operation started with `start` to the statistics of `op`.
+pub fn end(op: Operation, start: Measurement) {
+    if !cfg!(feature = "profile") {
+        return;
+    }
+    let cycles = DWT::cycle_count().wrapping_sub(start.cycles);
+    let bytes = flash_words_written().wrapping_sub(start.words) * 4;
+    let pages = flash_pages_erased().wrapping_sub(start.pages);
+    let block = unsafe { &mut *addr_of_mut!(profile_block) };
+    if block.valid != PROFILE_VALID {
+        block.stats = [OpStats::default(); OPERATIONS];
+        block.valid = PROFILE_VALID;
+    }
+    let stats = &mut block.stats[op.index()];
+    stats.min_cycles = if stats.count == 0 { cycles } else { stats.min_cycles.min(cycles) };
+    stats.max_cycles = stats.max_cycles.max(cycles);
+    stats.count = stats.count.saturating_add(1);
+    stats.total_cycles = stats.total_cycles.wrapping_add(cycles as u64);
+    stats.bytes_written = stats.bytes_written.wrapping_add(bytes as u64);
+    stats.pages_erased = stats.pages_erased.wrapping_add(pages);
+}
+
+// statistics of `op` since the last reset_profile(), None before the first
+// measurement
+pub fn op_stats(op: Operation) -> Option<OpStats> {
+    let block = unsafe { &*addr_of!(profile_block) };
+    if block.valid != PROFILE_VALID || block.stats[op.index()].count == 0 {
+        return None;
+    }
+    Some(block.stats[op.index()])
+}
+
+pub fn reset_profile() {
+    unsafe { (*addr_of_mut!(profile_block)).valid = 0 };
+}
@@ -0,0 +1,201 @@
+#![allow(unsafe_code, non_upper_case_globals)]
+use core::ptr::{addr_of, addr_of_mut};
+use stm32f3xx_hal_v2::pac::Peripherals;
+
+use super::bank::newest_intact;
+use super::error::RestoreError;
+use super::restore_newest;
+use super::hooks::{run_restore_hooks, EARLY_STAGES};
+use super::undo::roll_back_transaction;
+
+// Boot-time recovery. main() runs recover() right after initialization() has
+// set up the clocks, the FMC (F-RAM needs them) and the GPIOs, before the
+// application itself starts; the Clocks and Memory restore hooks (see
+// hooks.rs) can bring up the first two instead:
+//
+//   1. read and clear the RCC CSR reset flags
+//   2. run the Clocks and Memory restore hooks
+//   3. undo a transaction the reset interrupted (see undo.rs)
+//   4. look for a committed frame and decide:
+//        no frame                        ColdStart
+//        watchdog or low-power reset     SafeMode, resuming may well end in
+//                                        the same reset again
+//        anything else                   Resume, restore() does not return
+//                                        unless the frame fails validation,
+//                                        which falls back to ColdStart, or
+//                                        keeps getting resumed (loops.rs),
+//                                        which ends in SafeMode
+//
+// The decision is kept in F-RAM so the application can read it with
+// boot_report(), also after a resume.
+
+const RCC_CSR_LPWRRSTF: u32 = 1 << 31;
+const RCC_CSR_WWDGRSTF: u32 = 1 << 30;
+const RCC_CSR_IWDGRSTF: u32 = 1 << 29;
+const RCC_CSR_SFTRSTF: u32 = 1 << 28;
+const RCC_CSR_PORRSTF: u32 = 1 << 27;
+const RCC_CSR_PINRSTF: u32 = 1 << 26;
+const RCC_CSR_OBLRSTF: u32 = 1 << 25;
+const RCC_CSR_RMVF: u32 = 1 << 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetCause {
+    PowerOn,
+    Pin,
+    Software,
+    IndependentWatchdog,
+    WindowWatchdog,
+    LowPower,
+    OptionByteLoad,
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootDecision {
+    // nothing to resume, initialise from scratch
+    ColdStart,
+    // execution continues from the newest frame
+    Resume,
+    // there is a frame but the reset cause says not to trust it blindly, the
+    // application decides (restore(), restore_frame() or a cold start)
+    SafeMode,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BootReport {
+    pub cause: ResetCause,
+    // RCC CSR as read before the flags were cleared
+    pub reset_flags: u32,
+    pub decision: BootDecision,
+    // an interrupted transaction was rolled back
+    pub rolled_back: bool,
+    // newest committed frame
+    pub frame_seq: Option<u32>,
+    // why resuming failed, the decision is ColdStart then, SafeMode for
+    // RestoreError::RestoreLoop
+    pub restore_error: Option<RestoreError>,
+}
+
+const REPORT_VALID: u32 = 0xB007_0001;
+
+// BootReport as plain words, F-RAM contents are not initialised
+#[repr(C)]
+struct StoredReport {
+    valid: u32,
+    reset_flags: u32,
+    decision: u32,
+    rolled_back: u32,
+    has_frame: u32,
+    frame_seq: u32,
+    restore_error: u32,
+}
+
+#[link_section = ".fram_section"]
+static mut stored_report: StoredReport = StoredReport {
+    valid: 0,
+    reset_flags: 0,
+    decision: 0,
+    rolled_back: 0,
+    has_frame: 0,
+    frame_seq: 0,
+    restore_error: 0,
+};
+
+// Highest priority first, the pin flag is set along with most other resets.
+pub fn reset_cause(flags: u32) -> ResetCause {
+    if flags & RCC_CSR_IWDGRSTF != 0 {
+        ResetCause::IndependentWatchdog
+    } else if flags & RCC_CSR_WWDGRSTF != 0 {
+        ResetCause::WindowWatchdog
+    } else if flags & RCC_CSR_LPWRRSTF != 0 {
+        ResetCause::LowPower
+    } else if flags & RCC_CSR_SFTRSTF != 0 {
+        ResetCause::Software
+    } else if flags & RCC_CSR_PORRSTF != 0 {
+        ResetCause::PowerOn
+    } else if flags & RCC_CSR_OBLRSTF != 0 {
+        ResetCause::OptionByteLoad
+    } else if flags & RCC_CSR_PINRSTF != 0 {
+        ResetCause::Pin
+    } else {
+        ResetCause::Unknown
+    }
+}
+
+fn read_reset_flags() -> u32 {
+    unsafe {
+        let dp = Peripherals::steal();
+        let flags = dp.RCC.csr.read().bits();
+        dp.RCC.csr.modify(|r, w| w.bits(r.bits() | RCC_CSR_RMVF));
+        flags
+    }
+}
+
+fn store_report(report: &BootReport) {
+    let decision = match report.decision {
+        BootDecision::ColdStart => 0,
+        BootDecision::Resume => 1,
+        BootDecision::SafeMode => 2,
+    };
+    unsafe {
+        let stored = &mut *addr_of_mut!(stored_report);
+        stored.valid = 0;
+        stored.reset_flags = report.reset_flags;
+        stored.decision = decision;
+        stored.rolled_back = report.rolled_back as u32;
+        stored.has_frame = report.frame_seq.is_some() as u32;
+        stored.frame_seq = report.frame_seq.unwrap_or(0);
+        stored.restore_error = report.restore_error.map_or(0, |e| e.code());
+        stored.valid = REPORT_VALID;
+    }
+}
+
+// what recover() decided on this boot
+pub fn boot_report() -> Option<BootReport> {
+    let stored = unsafe { &*addr_of!(stored_report) };
+    if stored.valid != REPORT_VALID {
+        return None;
+    }
+    let decision = match stored.decision {
+        1 => BootDecision::Resume,
+        2 => BootDecision::SafeMode,
+        _ => BootDecision::ColdStart,
+    };
+    Some(BootReport {
+        cause: reset_cause(stored.reset_flags),
+        reset_flags: stored.reset_flags,
+        decision,
+        rolled_back: stored.rolled_back != 0,
+        frame_seq: if stored.has_frame != 0 { Some(stored.frame_seq) } else { None },
+        restore_error: RestoreError::from_code(stored.restore_error),
+    })
+}
+
+// Does not return when it resumes a frame, checkpoint() returns Resumed there.
+pub fn recover() -> BootReport {
+    let reset_flags = read_reset_flags();
+    let cause = reset_cause(reset_flags);
+    run_restore_hooks(&EARLY_STAGES);
+    let rolled_back = roll_back_transaction();
+    let frame_seq = newest_intact().map(|(_, header)| header.seq);
+
+    let decision = match (frame_seq, cause) {
+        (None, _) => BootDecision::ColdStart,
+        (Some(_), ResetCause::IndependentWatchdog | ResetCause::WindowWatchdog | ResetCause::LowPower) => BootDecision::SafeMode,
+        (Some(_), _) => BootDecision::Resume,
+    };
+    let mut report = BootReport { cause, reset_flags, decision, rolled_back, frame_seq, restore_error: None };
+    store_report(&report);
+
+    if decision == BootDecision::Resume {
+        if let Err(error) = restore_newest() {
+            report.decision = match error {
+                RestoreError::RestoreLoop => BootDecision::SafeMode,
+                _ => BootDecision::ColdStart,
+            };
+            report.restore_error = Some(error);
+            store_report(&report);
+        }
+    }
+    report
+}
@@ -0,0 +1,168 @@
+#![allow(unsafe_code, non_upper_case_globals)]
+use core::ptr::{self, addr_of, addr_of_mut};
+
+use super::frame::SectionWriter;
+use super::layout::{stack_start, stack_end};
+
+// Process stacks of tasks that run on PSP (an RTOS or a cooperative
+// scheduler). Every task stack is registered with its bounds and the place
+// the scheduler keeps the task's saved sp; a checkpoint stores each stack
+// from that sp up to its top, for the task running at the time from the live
+// PSP instead. The main stack is still the frame's stack image.
+//
+// Task section of a frame (FLAG_TASKS), after the register block:
+//
+//   count, then per stack: top, sp, (top - sp) / 4 words starting at sp
+//
+// Stacks are told apart by their top. restore() puts every stack back and
+// writes its sp to the registered slot before it switches to the saved
+// context; the application registers the same stacks again after a reset,
+// before calling restore().
+
+pub const MAX_STACKS: usize = 8;
+
+#[derive(Clone, Copy)]
+pub struct TaskStack {
+    pub bottom: u32,
+    // one past the highest word of the stack
+    pub top: u32,
+    // where the scheduler keeps the task's sp while it is not running
+    pub sp_slot: *mut u32,
+}
+
+static mut stacks: [Option<TaskStack>; MAX_STACKS] = [None; MAX_STACKS];
+
+fn registered() -> impl Iterator<Item = TaskStack> {
+    unsafe { (*addr_of!(stacks)).iter().flatten().copied() }
+}
+
+// Adds a task stack, registering the same top again replaces the entry.
+// Returns false for a stack that overlaps the main stack or another task
+// stack, or when the registry is full.
+pub fn register_stack(bottom: u32, top: u32, sp_slot: *mut u32) -> bool {
+    let overlaps = |b: u32, t: u32| bottom < t && b < top;
+    if bottom >= top || !bottom.is_multiple_of(4) || !top.is_multiple_of(4) || overlaps(stack_end(), stack_start()) {
+        return false;
+    }
+    if registered().any(|s| s.top != top && overlaps(s.bottom, s.top)) {
+        return false;
+    }
+    let table = unsafe { &mut *addr_of_mut!(stacks) };
+    let entry = match table.iter().position(|s| s.is_some_and(|s| s.top == top)) {
+        Some(index) => Some(index),
+        None => table.iter().position(|s| s.is_none()),
+    };
+    match entry {
+        Some(index) => {
+            table[index] = Some(TaskStack { bottom, top, sp_slot });
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn unregister_stack(top: u32) {
+    let table = unsafe { &mut *addr_of_mut!(stacks) };
+    for entry in table.iter_mut() {
+        if entry.is_some_and(|s| s.top == top) {
+            *entry = None;
+        }
+    }
+}
+
+// registered stack holding `sp`, sp == top counts as an empty stack
+pub fn stack_of(sp: u32) -> Option<TaskStack> {
+    registered().find(|s| s.bottom <= sp && sp <= s.top)
+}
+
+// sp to save for `stack`, `active_psp` for the task that was running
+fn saved_sp(stack: &TaskStack, active_psp: Option<u32>) -> Option<u32> {
+    let sp = match active_psp {
+        Some(psp) if stack.bottom <= psp && psp <= stack.top => psp,
+        _ => unsafe { ptr::read_volatile(stack.sp_slot) },
+    };
+    if stack.bottom <= sp && sp <= stack.top && sp.is_multiple_of(4) {
+        Some(sp)
+    } else {
+        None
+    }
+}
+
+// Stacks and saved sps of a checkpoint in progress, taken once so the
+// section is written with exactly the size the frame was planned with.
+// Stacks whose sp is not inside them are left out.
+pub struct TaskSnapshot {
+    stacks: [Option<(TaskStack, u32)>; MAX_STACKS],
+}
+
+impl TaskSnapshot {
+    pub fn take(active_psp: Option<u32>) -> TaskSnapshot {
+        let mut snapshot = TaskSnapshot { stacks: [None; MAX_STACKS] };
+        for (entry, stack) in snapshot.stacks.iter_mut().zip(registered()) {
+            *entry = saved_sp(&stack, active_psp).map(|sp| (stack, sp));
+        }
+        snapshot
+    }
+
+    fn saved(&self) -> impl Iterator<Item = (TaskStack, u32)> + '_ {
+        self.stacks.iter().flatten().copied()
+    }
+
+    // size of the task section in bytes, 0 if there is nothing to save
+    pub fn section_len(&self) -> u32 {
+        if self.saved().next().is_none() {
+            return 0;
+        }
+        4 + self.saved().map(|(stack, sp)| 8 + (stack.top - sp)).sum::<u32>()
+    }
+
+    pub fn write(&self, writer: &mut SectionWriter) {
+        if self.section_len() == 0 {
+            return;
+        }
+        writer.word(self.saved().count() as u32);
+        for (stack, sp) in self.saved() {
+            writer.word(stack.top);
+            writer.word(sp);
+            writer.copy(sp, stack.top - sp);
+        }
+    }
+}
+
+// Puts back the stacks of the task section at `address` (`len` bytes). Checks
+// the whole section against the registry first, nothing is written unless
+// every stack in it is registered.
+pub fn restore_tasks(address: u32, len: u32) -> bool {
+    let read = |offset: u32| unsafe { ptr::read_volatile((address + offset) as *const u32) };
+    for pass in 0..2 {
+        if len < 4 {
+            return false;
+        }
+        let mut offset = 4;
+        for _ in 0..read(0) {
+            if offset + 8 > len {
+                return false;
+            }
+            let (top, sp) = (read(offset), read(offset + 4));
+            let stack = match registered().find(|s| s.top == top) {
+                Some(stack) if stack.bottom <= sp && sp <= top && sp.is_multiple_of(4) => stack,
+                _ => return false,
+            };
+            let data = offset + 8;
+            if data + (top - sp) > len {
+                return false;
+            }
+            if pass == 1 {
+                for i in 0..(top - sp) / 4 {
+                    unsafe { ptr::write_volatile((sp + i * 4) as *mut u32, read(data + i * 4)) };
+                }
+                unsafe { ptr::write_volatile(stack.sp_slot, sp) };
+            }
+            offset = data + (top - sp);
+        }
+        if offset != len {
+            return false;
+        }
+    }
+    true
+}
@@ -0,0 +1,112 @@
+#![allow(unsafe_code, non_upper_case_globals)]
+use core::ptr::{self, addr_of, addr_of_mut};
+
+use super::layout::{undo_log_start, undo_log_end, persistent_start, persistent_end, data_start, stack_start};
+
+// Persistent state of the undo log written by save_variables(). Records are
+//
+//   address (4 bytes, little endian), size (1 byte), `size` bytes of old data
+//
+// from _undo_log_start up to `end`. start_atomic() marks a transaction
+// active, save_variables() moves `end` only once a record is complete and
+// end_atomic() clears it again, so after a reset an active transaction is
+// exactly one that was interrupted and its records can be undone.
+
+const UNDO_STATE_VALID: u32 = 0x0D00_0001;
+pub const RECORD_HEADER: u32 = 5;
+
+#[repr(C)]
+struct UndoState {
+    valid: u32,
+    active: u32,
+    end: u32,
+}
+
+#[link_section = ".fram_section"]
+static mut undo_state: UndoState = UndoState { valid: 0, active: 0, end: 0 };
+
+pub fn begin_transaction() {
+    unsafe {
+        let state = &mut *addr_of_mut!(undo_state);
+        state.active = 0;
+        state.end = undo_log_start();
+        state.valid = UNDO_STATE_VALID;
+        state.active = 1;
+    }
+}
+
+// a record up to `end` is complete
+pub fn record_logged(end: u32) {
+    unsafe { (*addr_of_mut!(undo_state)).end = end };
+}
+
+pub fn end_transaction() {
+    unsafe { (*addr_of_mut!(undo_state)).active = 0 };
+}
+
+// a transaction was started and never ended
+pub fn transaction_interrupted() -> bool {
+    let state = unsafe { &*addr_of!(undo_state) };
+    state.valid == UNDO_STATE_VALID && state.active != 0
+}
+
+// A variable the log may hold: in RAM or among the persistent F-RAM variables.
+// Anything else, the undo log and the stack shadow included, is never
+// written back, not even from a corrupt record.
+pub fn loggable(address: u32, size: u32) -> bool {
+    let inside = |start: u32, end: u32| address >= start && size <= end - start && address - start <= end - start - size;
+    inside(data_start(), stack_start()) || inside(persistent_start(), persistent_end())
+}
+
+fn byte(address: u32) -> u8 {
+    unsafe { ptr::read_volatile(address as *const u8) }
+}
+
+// start of the record following the one at `record`, None past `end`
+fn next_record(record: u32, end: u32) -> Option<u32> {
+    if record + RECORD_HEADER > end {
+        return None;
+    }
+    let next = record + RECORD_HEADER + byte(record + 4) as u32;
+    if next <= end {
+        Some(next)
+    } else {
+        None
+    }
+}
+
+// Writes the old data of an interrupted transaction back, newest record
+// first so a variable logged twice ends up with its oldest value. Returns
+// false if there was nothing to undo.
+pub fn roll_back_transaction() -> bool {
+    if !transaction_interrupted() {
+        return false;
+    }
+    let end = unsafe { (*addr_of!(undo_state)).end };
+    if end < undo_log_start() || end > undo_log_end() {
+        end_transaction();
+        return false;
+    }
+
+    let mut count = 0;
+    let mut record = undo_log_start();
+    while let Some(next) = next_record(record, end) {
+        record = next;
+        count += 1;
+    }
+    for n in (0..count).rev() {
+        let mut record = undo_log_start();
+        for _ in 0..n {
+            record = next_record(record, end).unwrap_or(end);
+        }
+        let address = (0..4).fold(0u32, |a, i| a | (byte(record + i) as u32) << (i * 8));
+        if !loggable(address, byte(record + 4) as u32) {
+            continue;
+        }
+        for i in 0..byte(record + 4) as u32 {
+            unsafe { ptr::write_volatile((address + i) as *mut u8, byte(record + RECORD_HEADER + i)) };
+        }
+    }
+    end_transaction();
+    true
+}
@@ -0,0 +1,79 @@
+use super::context::{REG_SP, REG_PC, REG_XPSR, REG_CONTROL, REG_MSP, REG_PSP, XPSR_IPSR, XPSR_ICI_IT};
+use super::error::RestoreError;
+use super::frame::FrameHeader;
+use super::globals::globals_match;
+use super::heap::heap_match;
+use super::layout::{stack_start, stack_end, text_start, text_end};
+use super::tasks::stack_of;
+
+// EPSR.T, the Thumb bit of an interrupted thread
+const XPSR_THUMB: u32 = 1 << 24;
+
+// Checks a frame before restore() touches anything: a frame that passes is
+// safe to jump into as far as the header and register block can tell.
+pub fn validate_frame(address: u32, header: &FrameHeader) -> Result<(), RestoreError> {
+    if !header.is_valid() {
+        return Err(RestoreError::BadHeader);
+    }
+    if !header.is_intact(address) {
+        return Err(RestoreError::Corrupt);
+    }
+    let register = |n: usize| header.register(address, n as u32);
+
+    // the optional sections have to end before the crc
+    let tasks_offset = header.tasks_offset(address);
+    if tasks_offset > header.crc_offset() || (header.has_tasks() && tasks_offset == header.crc_offset()) {
+        return Err(RestoreError::BadHeader);
+    }
+    let heap_offset = header.heap_offset(address);
+    if heap_offset.is_some_and(|offset| offset > tasks_offset) {
+        return Err(RestoreError::BadHeader);
+    }
+    if let Some(offset) = header.globals_offset() {
+        if !globals_match(address + offset, heap_offset.unwrap_or(tasks_offset) - offset) {
+            return Err(RestoreError::Globals);
+        }
+    }
+    if let Some(offset) = heap_offset {
+        if !heap_match(address + offset, tasks_offset - offset) {
+            return Err(RestoreError::Heap);
+        }
+    }
+
+    // the main stack image runs from the main sp up to _stack_start, on PSP
+    // that is the saved MSP and sp has to be in a registered task stack
+    let on_psp = register(REG_CONTROL) & 2 != 0;
+    let main_sp = if on_psp { register(REG_MSP) } else { register(REG_SP) };
+    if main_sp < stack_end() || main_sp >= stack_start() || !main_sp.is_multiple_of(4) {
+        return Err(RestoreError::BadStackPointer);
+    }
+    if on_psp && (!header.has_tasks() || stack_of(register(REG_PSP)).is_none()) {
+        return Err(RestoreError::BadStackPointer);
+    }
+    if header.stack_len != stack_start() - main_sp {
+        return Err(RestoreError::StackLengthMismatch);
+    }
+
+    // resuming always ends up in Thread mode, a frame taken in Handler mode
+    // (checkpoint() from a handler, or an exception that interrupted one) is
+    // not resumable
+    let xpsr = register(REG_XPSR);
+    if xpsr & XPSR_IPSR != 0 {
+        return Err(RestoreError::BadProgramCounter);
+    }
+
+    // checkpoint() saves a return address (bit 0 set), an exception frame
+    // the stacked pc with the Thumb state in xPSR; one taken inside an IT
+    // block or LDM/STM needs ICI/IT state resuming cannot restore
+    let pc = register(REG_PC);
+    let thumb = if header.is_exception() {
+        xpsr & XPSR_THUMB != 0 && xpsr & XPSR_ICI_IT == 0 && pc & 1 == 0
+    } else {
+        pc & 1 != 0
+    };
+    let target = pc & !1;
+    if !thumb || target < text_start() || target >= text_end() {
+        return Err(RestoreError::BadProgramCounter);
+    }
+    Ok(())
+}
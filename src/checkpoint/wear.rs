@@ -0,0 +1,87 @@
+#![allow(unsafe_code, non_upper_case_globals)]
+use core::ptr::addr_of_mut;
+use stm32f3xx_hal_v2::pac::FLASH;
+
+use super::bank::PAGE_SIZE;
+use super::layout::{checkpoint_start, checkpoint_end};
+use super::my_flash::{unlock, wait_ready, erase_page};
+
+// Per-page erase counters for the checkpoint area. They live in F-RAM so
+// counting an erase does not cost another flash erase.
+
+// memory.x checks that the checkpoint region is not larger than this
+pub const MAX_PAGES: usize = 256;
+const WEAR_MAGIC: u32 = 0x3EA2_0001;
+
+#[repr(C)]
+struct WearTable {
+    magic: u32,
+    erase_count: [u32; MAX_PAGES],
+}
+
+// magic is not set until the table has been formatted once
+#[link_section = ".fram_section"]
+static mut wear_table: WearTable = WearTable { magic: 0, erase_count: [0; MAX_PAGES] };
+
+pub struct WearStats {
+    pub pages: u32,
+    pub min: u32,
+    pub max: u32,
+    pub total: u32,
+}
+
+fn table() -> &'static mut WearTable {
+    unsafe {
+        let table = &mut *addr_of_mut!(wear_table);
+        if table.magic != WEAR_MAGIC {
+            table.erase_count = [0; MAX_PAGES];
+            table.magic = WEAR_MAGIC;
+        }
+        table
+    }
+}
+
+pub fn page_count() -> usize {
+    ((checkpoint_end() - checkpoint_start()) / PAGE_SIZE) as usize
+}
+
+fn page_index(page: u32) -> Option<usize> {
+    if (checkpoint_start()..checkpoint_end()).contains(&page) {
+        Some(((page - checkpoint_start()) / PAGE_SIZE) as usize)
+    } else {
+        None
+    }
+}
+
+// erase one page of the checkpoint area and count it
+pub fn erase_counted(flash: &mut FLASH, page: u32) {
+    unlock(flash);
+    wait_ready(flash);
+    erase_page(flash, page);
+    if let Some(index) = page_index(page) {
+        let table = table();
+        table.erase_count[index] = table.erase_count[index].saturating_add(1);
+    }
+}
+
+pub fn page_erase_count(page: u32) -> u32 {
+    match page_index(page) {
+        Some(index) => table().erase_count[index],
+        None => 0,
+    }
+}
+
+// total erases of `pages` pages starting at `start`
+pub fn range_erase_count(start: u32, pages: u32) -> u32 {
+    (0..pages).map(|i| page_erase_count(start + i * PAGE_SIZE)).sum()
+}
+
+pub fn wear_stats() -> WearStats {
+    let mut stats = WearStats { pages: page_count() as u32, min: u32::MAX, max: 0, total: 0 };
+    for count in table().erase_count[..page_count()].iter() {
+        stats.min = stats.min.min(*count);
+        stats.max = stats.max.max(*count);
+        stats.total = stats.total.saturating_add(*count);
+    }
+    stats
+}
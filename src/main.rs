@@ -17,7 +17,7 @@ use volatile::Volatile;
 use cortex_m::peripheral::NVIC;
 
 mod checkpoint;
-use checkpoint::{checkpoint, restore, delete_pg, delete_all_pg, transcation_log, execution_mode,start_atomic, end_atomic};
+use checkpoint::{checkpoint, restore, delete_pg, delete_all_pg, transcation_log, execution_mode,start_atomic, end_atomic, recover, BootDecision};
 
 #[link_section = ".fram_section"]
 static mut x:u8 = 1;
@@ -227,8 +227,18 @@ fn update(){
 pub extern "C" fn main() -> ! {
     //delete_pg(0x0803_0000 as u32);  //0x0807_F800
     initialization();
+    // resumes the last checkpoint after a power failure and does not return
+    // then; otherwise the application starts over
+    let report = recover();
     unsafe{rnd_array[4] = 1;}
-    update();
+    match report.decision {
+        // restore() only comes back to here when there was nothing to resume
+        BootDecision::ColdStart | BootDecision::Resume => update(),
+        // watchdog reset or restore loop, the saved state is not trusted:
+        // skip the update it was taken in, the checkpoint below replaces it.
+        // The cause stays available through boot_report()
+        BootDecision::SafeMode => {}
+    }
     checkpoint(false);
   
     // exit QEMU